@@ -1,4 +1,4 @@
-use binrw::{binrw, BinRead, BinResult};
+use binrw::{binrw, BinRead, BinResult, BinWrite};
 use hex_literal::hex;
 use sha1::{Digest, Sha1};
 use thiserror::Error;
@@ -23,12 +23,14 @@ pub trait HashHex {
     fn to_hex(&self) -> String;
 }
 
-impl HashHex for BbShaHash {
+impl HashHex for [u8] {
     fn to_hex(&self) -> String {
-        self.map(|b| format!("{b:02X}")).join("")
+        self.iter().map(|b| format!("{b:02X}")).collect()
     }
 }
 
+/// The per-console Virage2 block. It stores the SK's hash but not the SA1's, which is only kept in
+/// [`CmdHead::hash`]
 #[binrw]
 #[derive(Debug)]
 pub struct Virage2 {
@@ -55,10 +57,35 @@ pub struct Virage2 {
 impl Virage2 {
     const NUM_ROM_PATCH_WORDS: usize = 16;
 
-    const SIZE: usize = 256;
+    pub const SIZE: usize = 256;
 
     const CSUM_MAGIC: u32 = 0x00BBC0DE;
 
+    const CSUM_ADJUST_OFFSET: usize = 248;
+
+    fn checksum(buf: &[u8]) -> Wrapping<u32> {
+        buf.chunks_exact(4)
+            .map(|i| u32::from_be_bytes(i.try_into().unwrap()))
+            .map(Wrapping)
+            .sum()
+    }
+
+    /// Serialises the Virage2, replacing csum_adjust so that the checksum is valid
+    pub fn to_buf(&self) -> Vec<u8> {
+        let mut cursor = Cursor::new(Vec::with_capacity(Self::SIZE));
+        self.write_be(&mut cursor)
+            .expect("writing to a Vec can't fail");
+        let mut buf = cursor.into_inner();
+
+        let adjust = &mut buf[Self::CSUM_ADJUST_OFFSET..Self::CSUM_ADJUST_OFFSET + 4];
+        adjust.fill(0);
+        let csum = Self::checksum(&buf);
+        buf[Self::CSUM_ADJUST_OFFSET..Self::CSUM_ADJUST_OFFSET + 4]
+            .copy_from_slice(&(Wrapping(Self::CSUM_MAGIC) - csum).0.to_be_bytes());
+
+        buf
+    }
+
     pub fn read_from_buf(buf: &[u8]) -> BinResult<Self> {
         if buf.len() != Self::SIZE {
             return Err(binrw::Error::AssertFail {
@@ -71,11 +98,7 @@ impl Virage2 {
             });
         }
 
-        let csum: Wrapping<_> = buf
-            .chunks_exact(4)
-            .map(|i| u32::from_be_bytes(i.try_into().unwrap()))
-            .map(Wrapping)
-            .sum();
+        let csum = Self::checksum(buf);
 
         if csum.0 != Self::CSUM_MAGIC {
             return Err(binrw::Error::BadMagic {
@@ -99,6 +122,8 @@ pub enum BootromError {
     InvalidHash(String),
 }
 
+pub const BOOTROM_SIZE: usize = 0x2000;
+
 pub const SK_KEY_START: usize = 0x1460;
 pub const SK_IV_START: usize = 0x1470;
 
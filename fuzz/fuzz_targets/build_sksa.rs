@@ -0,0 +1,44 @@
+//! Feeds arbitrary SKSA info blocks, Virage2s, bootroms and payloads through the build path, which
+//! should only ever return errors; run with `cargo +nightly fuzz run build_sksa`
+#![no_main]
+
+use bbbs::{build_sksa, build_sksa_with_options, SkCheck};
+use libfuzzer_sys::fuzz_target;
+
+const SK_SIZE: usize = 64 * 1024;
+const SA1_INFO_BLOCK_SIZE: usize = 16 * 1024;
+const VIRAGE2_SIZE: usize = 256;
+
+/// Largest SA1 size to let through; anything bigger only tests the allocator
+const MAX_SA1_SIZE: u32 = 0x100000;
+
+fuzz_target!(|input: (Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>, bool)| {
+    let (payload, info, mut virage2, bootrom, fix_checksum) = input;
+
+    // the SK only feeds the hash check, so the fuzzed bytes start at the info block; it's padded so
+    // the CmdHead is always reached
+    let mut sksa = vec![0; SK_SIZE];
+    sksa.extend(info);
+    if sksa.len() < SK_SIZE + SA1_INFO_BLOCK_SIZE {
+        sksa.resize(SK_SIZE + SA1_INFO_BLOCK_SIZE, 0);
+    }
+
+    let size = &mut sksa[SK_SIZE + 12..SK_SIZE + 16];
+    if u32::from_be_bytes(size.try_into().unwrap()) > MAX_SA1_SIZE {
+        size.copy_from_slice(&MAX_SA1_SIZE.to_be_bytes());
+    }
+
+    // a random Virage2 almost never has a valid checksum, which would stop every input at the
+    // first check
+    if fix_checksum && virage2.len() >= VIRAGE2_SIZE {
+        virage2[248..252].fill(0);
+        let sum = virage2[..VIRAGE2_SIZE]
+            .chunks_exact(4)
+            .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+            .fold(0u32, u32::wrapping_add);
+        virage2[248..252].copy_from_slice(&0x00BBC0DEu32.wrapping_sub(sum).to_be_bytes());
+    }
+
+    let _ = build_sksa(payload.clone(), &sksa, &virage2, &bootrom);
+    let _ = build_sksa_with_options(payload, &sksa, &virage2, SkCheck::Skip, &Default::default());
+});
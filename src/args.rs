@@ -1,29 +1,157 @@
 use anyhow::Result;
-use clap::Parser;
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use memmap2::MmapMut;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+
+use bb::{BbAesIv, BbAesKey, BbEccPrivateKey, BbEccPublicKey, BbShaHash};
+
+use crate::{
+    BuildOptions, CipherMode, Endian, InputFormat, IvSource, EXIT_CODES, ROM_HEADER_SIZE, SK_SIZE,
+};
 
 use std::ffi::OsString;
 use std::fmt::{self, Display, Formatter};
-use std::fs::{read, read_to_string, write};
-use std::io::{stdout, Error, ErrorKind, Read, Write};
+use std::fs::{read, read_to_string, remove_file, rename, File, OpenOptions};
+use std::io::{
+    stderr, stdin, stdout, BufReader, BufWriter, Error, ErrorKind, IsTerminal, Read, Write,
+};
+use std::num::ParseIntError;
 use std::path::{Path, PathBuf};
 
+fn temp_path(path: &Path) -> Result<PathBuf, Error> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+    Ok(path.with_file_name(format!(
+        ".{}.tmp{}",
+        name.to_string_lossy(),
+        std::process::id()
+    )))
+}
+
+fn write_atomic(path: &Path, data: &[u8]) -> Result<(), Error> {
+    let tmp = temp_path(path)?;
+
+    let result = File::create(&tmp)
+        .and_then(|mut f| f.write_all(data).and_then(|_| f.sync_all()))
+        .and_then(|_| rename(&tmp, path));
+    if result.is_err() {
+        let _ = remove_file(&tmp);
+    }
+    result
+}
+
+/// A memory-mapped temporary file that replaces `path` once finished
 #[derive(Debug)]
+pub struct MappedFile {
+    map: MmapMut,
+    tmp: PathBuf,
+    path: PathBuf,
+}
+
+impl MappedFile {
+    fn create(path: &Path, len: usize) -> Result<Self, Error> {
+        let tmp = temp_path(path)?;
+
+        let map = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&tmp)
+            .and_then(|f| {
+                f.set_len(len as u64)?;
+                // SAFETY: the temporary file is private to this process until it is renamed
+                unsafe { MmapMut::map_mut(&f) }
+            });
+
+        match map {
+            Ok(map) => Ok(Self {
+                map,
+                tmp,
+                path: path.to_owned(),
+            }),
+            Err(e) => {
+                let _ = remove_file(&tmp);
+                Err(e)
+            }
+        }
+    }
+
+    pub fn finish(&self) -> Result<(), Error> {
+        let result = self.map.flush().and_then(|_| rename(&self.tmp, &self.path));
+        if result.is_err() {
+            let _ = remove_file(&self.tmp);
+        }
+        result
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl AsMut<[u8]> for MappedFile {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.map
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum IOType {
     Stdin,
     Stdout,
+    Stderr,
     File(PathBuf),
+    #[cfg(feature = "net")]
+    Url(String),
+}
+
+#[cfg(feature = "net")]
+fn fetch(url: &str) -> Result<impl Read, Error> {
+    ureq::get(url)
+        .call()
+        .map(|r| r.into_body().into_reader())
+        .map_err(Error::other)
+}
+
+/// Fails instead of letting a read from an interactive stdin block with no explanation
+fn check_stdin_piped() -> Result<(), Error> {
+    if stdin().is_terminal() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "stdin is a terminal; pipe the input in, or give a file instead of \"-\"",
+        ));
+    }
+    Ok(())
 }
 
 impl IOType {
     pub fn read(&self) -> Result<Vec<u8>, Error> {
+        self.read_with_capacity(0)
+    }
+
+    /// `capacity` is only a hint for stdin; reading past it is fine
+    pub fn read_with_capacity(&self, capacity: usize) -> Result<Vec<u8>, Error> {
         match self {
             Self::Stdin => {
-                let mut rv = vec![];
+                check_stdin_piped()?;
+                let mut rv = Vec::with_capacity(capacity);
                 std::io::stdin().lock().read_to_end(&mut rv)?;
                 Ok(rv)
             }
-            Self::Stdout => Err(Error::from(ErrorKind::Unsupported)),
+            Self::Stdout | Self::Stderr => Err(Error::from(ErrorKind::Unsupported)),
             Self::File(path) => read(path),
+            #[cfg(feature = "net")]
+            Self::Url(url) => {
+                let mut rv = Vec::with_capacity(capacity);
+                fetch(url)?.read_to_end(&mut rv)?;
+                Ok(rv)
+            }
         }
         .map_err(|e| Error::new(e.kind(), format!("{} ({})", e, self)))
     }
@@ -31,12 +159,19 @@ impl IOType {
     pub fn read_string(&self) -> Result<String, Error> {
         match self {
             Self::Stdin => {
+                check_stdin_piped()?;
                 let mut rv = String::new();
                 std::io::stdin().lock().read_to_string(&mut rv)?;
                 Ok(rv)
             }
-            Self::Stdout => Err(Error::from(ErrorKind::Unsupported)),
+            Self::Stdout | Self::Stderr => Err(Error::from(ErrorKind::Unsupported)),
             Self::File(path) => read_to_string(path),
+            #[cfg(feature = "net")]
+            Self::Url(url) => {
+                let mut rv = String::new();
+                fetch(url)?.read_to_string(&mut rv)?;
+                Ok(rv)
+            }
         }
         .map_err(|e| Error::new(e.kind(), format!("{} ({})", e, self)))
     }
@@ -45,37 +180,86 @@ impl IOType {
         match self {
             Self::Stdin => Err(Error::from(ErrorKind::Unsupported)),
             Self::Stdout => stdout().write(data.as_ref()),
-            Self::File(path) => write(path, &data).and(Ok(data.as_ref().len())),
+            Self::Stderr => stderr().write(data.as_ref()),
+            Self::File(path) => write_atomic(path, data.as_ref()).and(Ok(data.as_ref().len())),
+            #[cfg(feature = "net")]
+            Self::Url(_) => Err(Error::from(ErrorKind::Unsupported)),
+        }
+    }
+
+    /// Creates a memory-mapped output of `len` bytes; `None` for streams
+    pub fn map_output(&self, len: usize) -> Result<Option<MappedFile>, Error> {
+        match self {
+            Self::File(path) => MappedFile::create(path, len).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Opens a streaming reader: a buffered file, locked stdin, or the response body of a URL
+    pub fn reader(&self) -> Result<Box<dyn Read + '_>, Error> {
+        match self {
+            Self::Stdin => check_stdin_piped().map(|()| Box::new(stdin().lock()) as Box<dyn Read>),
+            Self::Stdout | Self::Stderr => Err(Error::from(ErrorKind::Unsupported)),
+            Self::File(path) => File::open(path).map(|f| Box::new(BufReader::new(f)) as _),
+            #[cfg(feature = "net")]
+            Self::Url(url) => fetch(url).map(|r| Box::new(r) as _),
+        }
+        .map_err(|e| Error::new(e.kind(), format!("{} ({})", e, self)))
+    }
+
+    /// Opens a streaming writer: a buffered file, or locked stdout/stderr; unlike [`Self::write`],
+    /// files are written in place rather than atomically
+    pub fn writer(&self) -> Result<Box<dyn Write + '_>, Error> {
+        match self {
+            Self::Stdin => Err(Error::from(ErrorKind::Unsupported)),
+            Self::Stdout => Ok(Box::new(stdout().lock()) as Box<dyn Write>),
+            Self::Stderr => Ok(Box::new(stderr().lock()) as Box<dyn Write>),
+            Self::File(path) => File::create(path).map(|f| Box::new(BufWriter::new(f)) as _),
+            #[cfg(feature = "net")]
+            Self::Url(_) => Err(Error::from(ErrorKind::Unsupported)),
+        }
+        .map_err(|e| Error::new(e.kind(), format!("{} ({})", e, self)))
+    }
+
+    pub fn read_prefix(&self, len: usize) -> Result<Vec<u8>, Error> {
+        let mut rv = vec![];
+        self.reader()?
+            .take(len as u64)
+            .read_to_end(&mut rv)
+            .map_err(|e| Error::new(e.kind(), format!("{} ({})", e, self)))?;
+        Ok(rv)
+    }
+
+    pub fn canonical_path(&self) -> Option<PathBuf> {
+        match self {
+            Self::File(path) => path.canonicalize().ok(),
+            _ => None,
         }
     }
 
-    fn input<T: AsRef<str>>(path: T) -> Self {
+    pub fn input<T: AsRef<str>>(path: T) -> Self {
         match path.as_ref() {
             "-" => Self::Stdin,
+            #[cfg(feature = "net")]
+            p if p.starts_with("http://") || p.starts_with("https://") => Self::Url(p.to_owned()),
             p => Self::File(PathBuf::from(p)),
         }
     }
 
-    fn output<T: AsRef<str>>(path: T) -> Self {
+    pub fn output<T: AsRef<str>>(path: T) -> Self {
         match path.as_ref() {
             "-" => Self::Stdout,
             p => Self::File(PathBuf::from(p)),
         }
     }
 
-    fn derive_input<F: FnOnce(&PathBuf) -> PathBuf>(&self, f: F) -> Self {
-        match self {
-            Self::Stdin => Self::Stdin,
-            Self::Stdout => Self::Stdin,
-            Self::File(p) => Self::File(f(p)),
-        }
-    }
-
-    fn derive_output<F: FnOnce(&PathBuf) -> PathBuf>(&self, f: F) -> Self {
+    /// Derive a default output from an input: files are renamed with `f`, anything else goes to stdout
+    pub fn derive_output<F: FnOnce(&PathBuf) -> PathBuf>(&self, f: F) -> Self {
         match self {
-            Self::Stdin => Self::Stdout,
-            Self::Stdout => Self::Stdout,
             Self::File(p) => Self::File(f(p)),
+            Self::Stdin | Self::Stdout | Self::Stderr => Self::Stdout,
+            #[cfg(feature = "net")]
+            Self::Url(_) => Self::Stdout,
         }
     }
 }
@@ -88,83 +272,1552 @@ impl Display for IOType {
             match self {
                 Self::Stdin => "stdin".to_string(),
                 Self::Stdout => "stdout".to_string(),
+                Self::Stderr => "stderr".to_string(),
                 Self::File(f) => f.display().to_string(),
+                #[cfg(feature = "net")]
+                Self::Url(url) => url.clone(),
             }
         )
     }
 }
 
+fn strip_hex_prefix(s: &str) -> &str {
+    s.strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s)
+}
+
+fn parse_hex_u8(s: &str) -> Result<u8, ParseIntError> {
+    u8::from_str_radix(strip_hex_prefix(s), 16)
+}
+
+fn parse_hex_u32(s: &str) -> Result<u32, ParseIntError> {
+    u32::from_str_radix(strip_hex_prefix(s), 16)
+}
+
+fn parse_hex_usize(s: &str) -> Result<usize, ParseIntError> {
+    usize::from_str_radix(strip_hex_prefix(s), 16)
+}
+
+fn parse_hex_array<const N: usize>(s: &str) -> Result<[u8; N], String> {
+    let s = strip_hex_prefix(s);
+
+    if !s.is_ascii() || s.len() != N * 2 {
+        return Err(format!("expected {} hex digits", N * 2));
+    }
+
+    let mut rv = [0; N];
+    for (b, c) in rv.iter_mut().zip(s.as_bytes().chunks_exact(2)) {
+        *b = u8::from_str_radix(std::str::from_utf8(c).unwrap(), 16).map_err(|e| e.to_string())?;
+    }
+
+    Ok(rv)
+}
+
+fn parse_extra_payload(s: &str) -> Result<(usize, String), String> {
+    let (offset, file) = s
+        .split_once(':')
+        .ok_or_else(|| "expected <offset>:<file>".to_owned())?;
+    let offset = parse_hex_usize(offset).map_err(|e| e.to_string())?;
+    Ok((offset, file.to_owned()))
+}
+
+fn parse_patch(s: &str) -> Result<(usize, Vec<u8>), String> {
+    let (offset, bytes) = s
+        .split_once(':')
+        .ok_or_else(|| "expected <offset>:<bytes>".to_owned())?;
+    let offset = parse_hex_usize(offset).map_err(|e| e.to_string())?;
+
+    let bytes = strip_hex_prefix(bytes);
+    if bytes.is_empty() || !bytes.is_ascii() || !bytes.len().is_multiple_of(2) {
+        return Err("expected an even, non-zero number of hex digits".to_owned());
+    }
+    let bytes = bytes
+        .as_bytes()
+        .chunks_exact(2)
+        .map(|c| u8::from_str_radix(std::str::from_utf8(c).unwrap(), 16))
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+
+    Ok((offset, bytes))
+}
+
+fn exit_code_help() -> String {
+    let mut rv = String::from("Exit codes:\n  1  any other error\n  2  invalid arguments\n");
+    for (name, code) in EXIT_CODES {
+        rv.push_str(&format!("  {code:<2} {name}\n"));
+    }
+    rv
+}
+
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, after_long_help = exit_code_help())]
 struct Cli {
+    /// Log each stage of processing to stderr (there's no -v for this, as -v is the Virage2)
+    #[arg(long, global = true)]
+    verbose: bool,
+
+    /// Don't print warnings, or a summary line after writing output
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// TOML file with default paths for sksa/virage2/bootrom and default build options
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: CliCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum CliCommand {
+    /// Build a BBBS SKSA from a payload
+    Build(Box<BuildCli>),
+
+    /// Extract the payload from a BBBS SKSA
+    Extract(ExtractCli),
+
+    /// Check an SKSA's SK hash against a Virage2
+    Verify(VerifyCli),
+
+    /// Check that a built SKSA's SA1 holds a given payload
+    Validate(ValidateCli),
+
+    /// Print the SA1 CmdHead parsed from an SKSA
+    Info(InfoCli),
+
+    /// List the SA command blocks in an SKSA with their offsets and sizes
+    List(ListCli),
+
+    /// Print the keys derived from an SKSA, Virage2 and bootrom
+    Keys(KeysCli),
+
+    /// Re-encrypt a BBBS SKSA's SA1 for a different console
+    Recrypt(RecryptCli),
+
+    /// Patch bytes in a BBBS SKSA's SA1 and re-encrypt it
+    Patch(PatchCli),
+
+    /// Build BBBS SKSAs from several payloads, deriving the keys only once
+    Batch(BatchCli),
+
+    /// Construct a Virage2 from its fields, with a valid checksum
+    #[command(name = "mkvirage2")]
+    MkVirage2(MkVirage2Cli),
+
+    /// Combine an SKSA, Virage2 and bootrom into a single bundle for --bundle
+    Bundle(BundleCli),
+}
+
+/// The `--sk-size` option shared by every command that reads an SKSA
+#[derive(clap::Args, Debug)]
+struct SkSizeCli {
+    /// Size of the SK at the start of the SKSA, in bytes; must be a multiple of the AES block size
+    #[arg(long, default_value_t = SK_SIZE)]
+    sk_size: usize,
+}
+
+#[derive(clap::Args, Debug)]
+struct KeyCli {
+    /// Input Virage2 (used for key derivation)
+    #[arg(short, long)]
+    virage2: Option<String>,
+
+    /// Offset of the Virage2 within a larger dump, in hex
+    #[arg(long, value_parser = parse_hex_usize)]
+    virage2_offset: Option<usize>,
+
+    /// Input bootrom (used for key derivation)
+    #[arg(short, long)]
+    bootrom: Option<String>,
+
+    /// Offset of the bootrom within a larger dump, in hex
+    #[arg(long, value_parser = parse_hex_usize)]
+    bootrom_offset: Option<usize>,
+
+    /// Expected plaintext SK hash, in hex, to check the Virage2 against instead of decrypting the SK
+    #[arg(long, value_parser = parse_hex_array::<20>, conflicts_with = "bootrom")]
+    sk_hash: Option<BbShaHash>,
+
+    /// Skip SK verification entirely
+    #[arg(long, conflicts_with_all = ["bootrom", "sk_hash"])]
+    no_verify: bool,
+
+    /// Reference plaintext SK to compare the decrypted SK against byte-for-byte
+    #[arg(long, conflicts_with_all = ["sk_hash", "no_verify"])]
+    expect_sk: Option<String>,
+
+    /// Bundle (from the bundle subcommand) to take the SKSA, Virage2 and bootrom from when they aren't given separately
+    #[arg(long)]
+    bundle: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct BuildOptionsCli {
+    /// Entrypoint to write into the SA1 ROM header, in hex [default: 80300000]
+    #[arg(short, long, value_parser = parse_hex_u32)]
+    entrypoint: Option<u32>,
+
+    /// Byte order to write the entrypoint in
+    #[arg(long, value_enum, default_value_t = Endian::Big)]
+    endian: Endian,
+
+    /// Size of the SA1 ROM header, in bytes
+    #[arg(long, default_value_t = ROM_HEADER_SIZE)]
+    header_size: usize,
+
+    /// Offset of the payload past the end of the ROM header (and stub), in hex
+    #[arg(long, value_parser = parse_hex_usize, default_value = "0")]
+    payload_offset: usize,
+
+    /// Allow a payload that already looks like a built SA1 or SKSA
+    #[arg(long)]
+    allow_prebuilt: bool,
+
+    /// Allow an empty payload, producing a header-only SA1
+    #[arg(long)]
+    allow_empty: bool,
+
+    /// Gzip the payload before packing it into the SA1
+    #[arg(short, long, overrides_with = "no_compress")]
+    compress: bool,
+
+    /// Don't gzip the payload, even if the config says to
+    #[arg(long, overrides_with = "compress")]
+    no_compress: bool,
+
+    /// Decrypt the built SA1 and check it matches the plaintext
+    #[arg(long)]
+    check_roundtrip: bool,
+
+    /// Which SA to rebuild (1 for SA1, 2 for SA2, ...)
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    sa_index: u64,
+
+    #[command(flatten)]
+    sk_size: SkSizeCli,
+
+    /// Byte to pad the SA1 with, in hex
+    #[arg(long, value_parser = parse_hex_u8, default_value = "00")]
+    pad_byte: u8,
+
+    /// Only output the encrypted SA1, without the SK and info block
+    #[arg(long)]
+    sa1_only: bool,
+
+    /// IV to encrypt the SA1 with; a zero IV makes SA1s with a common prefix recognisable, so only use it to match existing images
+    #[arg(long, value_enum, default_value_t = IvSource::Header)]
+    sa1_iv_from: IvSource,
+
+    /// How to encrypt the SA1; CTR uses the IV as the initial counter, and is only for experimental SKSAs
+    #[arg(long, value_enum, default_value_t = CipherMode::Cbc)]
+    cipher_mode: CipherMode,
+
+    /// Common IV (32 hex digits) to derive the SA1 key with, instead of the one in the info block
+    #[arg(long, value_parser = parse_hex_array::<16>)]
+    common_cmd_iv: Option<BbAesIv>,
+
+    /// Show progress while encrypting the SA1
+    #[arg(long)]
+    progress: bool,
+
+    /// Keep any bytes after the rebuilt SA (e.g. later SAs) in the output
+    #[arg(long, conflicts_with = "sa1_only")]
+    preserve_tail: bool,
+
+    /// Whether `entrypoint` came from the config rather than the command line
+    #[arg(skip)]
+    config_entrypoint: bool,
+}
+
+/// Inputs that, along with [`BuildOptionsCli`], decide how the payload is laid out in the SA1
+#[derive(clap::Args, Debug)]
+struct LayoutCli {
+    /// Format of the payload; loadable segments of an ELF are flattened, and its entry point is used unless --entrypoint is given
+    #[arg(long, value_enum, default_value_t = InputFormat::Raw)]
+    input_format: InputFormat,
+
+    /// Loader stub to place between the ROM header and the payload; --entrypoint should point at it
+    #[arg(long, requires = "entrypoint")]
+    sa1_stub: Option<String>,
+
+    /// Another file to place in the SA1 after the payload, as <offset>:<file> with the offset in hex from the start of the SA1; may be given more than once
+    #[arg(long, value_name = "OFFSET:FILE", value_parser = parse_extra_payload)]
+    extra_payload: Vec<(usize, String)>,
+}
+
+#[derive(clap::Args, Debug)]
+struct BuildCli {
     /// Payload; "-" for stdin
     #[arg(default_value_t = String::from("-"))]
     infile: String,
 
     /// Input SKSA
+    #[arg(short, long, required_unless_present_any = ["sk", "bundle"], conflicts_with = "sk")]
+    sksa: Option<String>,
+
+    /// Input SK, to combine with --cmd instead of reading a whole SKSA
+    #[arg(long, requires = "cmd")]
+    sk: Option<String>,
+
+    /// Input SA command block (the SA1 info block, optionally followed by the SA1), to combine with --sk
+    #[arg(long, requires = "sk")]
+    cmd: Option<String>,
+
+    #[command(flatten)]
+    keys: KeyCli,
+
+    #[command(flatten)]
+    options: BuildOptionsCli,
+
+    #[command(flatten)]
+    layout: LayoutCli,
+
+    /// [testing] SA1 key to use instead of deriving it from the Virage2, in hex
+    #[arg(long, value_parser = parse_hex_array::<16>, requires = "sa1_iv")]
+    sa1_key: Option<BbAesKey>,
+
+    /// [testing] SA1 IV to use instead of the one in the CmdHead, in hex
+    #[arg(long, value_parser = parse_hex_array::<16>, requires = "sa1_key")]
+    sa1_iv: Option<BbAesIv>,
+
+    /// Check the SK hash but only warn if it doesn't match, then continue building
+    #[arg(long, conflicts_with = "no_verify")]
+    ignore_sk_hash: bool,
+
+    /// Expected SHA-1 hash of the payload, in hex; checked before anything else is done
+    #[arg(long, value_parser = parse_hex_array::<20>)]
+    payload_sha1: Option<BbShaHash>,
+
+    /// Expected SHA-1 hash of the input SKSA's existing SA1 plaintext, in hex; checked before rebuilding
+    #[arg(long, value_parser = parse_hex_array::<20>, conflicts_with = "sa1_key")]
+    expect_sa1_hash: Option<BbShaHash>,
+
+    /// RSA private key (PKCS#8 or PKCS#1 PEM) to sign the encrypted SA1 with; the RSASSA-PKCS1-v1_5 SHA-1 signature is appended after the SA1
+    #[cfg(feature = "sign")]
+    #[arg(long, conflicts_with = "preserve_tail")]
+    sign_key: Option<String>,
+
+    /// Print the maximum payload size for the SKSA and exit
+    #[arg(long)]
+    max_payload: bool,
+
+    /// Expected payload size when reading it from stdin, used to preallocate (a wrong guess is harmless)
+    #[arg(long)]
+    stdin_len: Option<usize>,
+
+    /// Print the SHA-1 hash of the plaintext SA1 to stderr
+    #[arg(long)]
+    print_sa1_hash: bool,
+
+    /// Print the CRC32 of the output to stderr
+    #[arg(long)]
+    crc: bool,
+
+    /// Print how many bytes of padding were added to the SA1 to stderr
+    #[arg(long)]
+    print_padding: bool,
+
+    /// Write the plaintext SA1 (header, payload and padding) to a file before it is encrypted
+    #[arg(long)]
+    dump_plain_sa1: Option<String>,
+
+    /// Write the SHA-1 hash of the output to a file [default: <outfile>.sha1, or stderr]
+    #[arg(long, num_args = 0..=1, require_equals = true)]
+    output_hash_file: Option<Option<String>>,
+
+    /// Print a JSON summary of the build (or the error) [default: stdout, or stderr if writing the SKSA to stdout]
+    #[arg(long, num_args = 0..=1, require_equals = true)]
+    json: Option<Option<String>>,
+
+    /// Allow the output to overwrite one of the inputs
     #[arg(short, long)]
-    sksa: String,
+    force: bool,
 
-    /// Input Virage2 (used for key derivation)
+    /// Print the byte ranges where the output differs from this SKSA (nothing is written unless an outfile is given)
+    #[arg(long)]
+    compare: Option<String>,
+
+    /// Previous plaintext SA1 (e.g. from --dump-plain-sa1) to report the first AES block that changed since
+    #[arg(long)]
+    cbc_common_prefix: Option<String>,
+
+    /// Print how long each stage of the build took, and how many bytes it processed, to stderr
+    #[arg(long)]
+    stats: bool,
+
+    /// Write a JSON description of the output's layout (region offsets and sizes, and hashes) to a file
+    #[arg(long)]
+    manifest: Option<String>,
+
+    /// Fail instead of warning when the rebuilt SA would share its key and IV with another SA
+    #[arg(long)]
+    strict: bool,
+
+    /// Write every intermediate artifact (CmdHead, plaintext SA1 and output, and with --dump-keys the decrypted SK and SA1 key) to a directory
+    #[arg(long, value_name = "DIR")]
+    dump_all: Option<PathBuf>,
+
+    /// Acknowledge that --dump-all will write secret key material
+    #[arg(long, requires = "dump_all")]
+    dump_keys: bool,
+
+    /// Run the whole build, but don't write the output or any of the side outputs (dumps, hash file, JSON summary, manifest)
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Read the output file back after writing it and check it matches (skipped for stdout)
+    #[arg(long)]
+    verify_write: bool,
+
+    /// Write the output through a memory-mapped file instead of building it in memory (files only)
+    #[arg(long)]
+    mmap: bool,
+
+    /// Directory to put the output in when it is derived from the infile
+    #[arg(long)]
+    outdir: Option<PathBuf>,
+
+    /// Extension to give the output when it is derived from the infile
+    #[arg(long, default_value = "sksa")]
+    out_ext: String,
+
+    /// Output BBBS SKSA; "-" for stdout [default: <infile>.<out-ext> or -]
+    outfile: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ExtractCli {
+    /// Input BBBS SKSA; "-" for stdin
+    #[arg(default_value_t = String::from("-"))]
+    infile: String,
+
+    #[command(flatten)]
+    keys: KeyCli,
+
+    #[command(flatten)]
+    sk_size: SkSizeCli,
+
+    /// Size of the SA1 ROM header to strip, in bytes
+    #[arg(long, default_value_t = ROM_HEADER_SIZE)]
+    header_size: usize,
+
+    /// Offset of the payload past the end of the ROM header, in hex
+    #[arg(long, value_parser = parse_hex_usize, default_value = "0")]
+    payload_offset: usize,
+
+    /// Trim trailing zero padding from the payload
     #[arg(short, long)]
-    virage2: String,
+    trim: bool,
 
-    /// Input bootrom (used for key derivation)
+    /// Never trim the payload below this many bytes, in hex
+    #[arg(long, value_parser = parse_hex_usize, default_value = "0", requires = "trim")]
+    min_size: usize,
+
+    /// Only trim whole AES blocks past the last one containing a non-zero byte
+    #[arg(long, requires = "trim")]
+    trim_aligned: bool,
+
+    /// IV the SA1 was encrypted with
+    #[arg(long, value_enum, default_value_t = IvSource::Header)]
+    sa1_iv_from: IvSource,
+
+    /// How the SA1 was encrypted
+    #[arg(long, value_enum, default_value_t = CipherMode::Cbc)]
+    cipher_mode: CipherMode,
+
+    /// Allow the output to overwrite one of the inputs
     #[arg(short, long)]
-    bootrom: String,
+    force: bool,
 
-    /// Output BBBS SKSA; "-" for stdout [default: <infile>.sksa or -]
+    /// Output payload; "-" for stdout [default: <infile>.bin or -]
     outfile: Option<String>,
 }
 
-#[derive(Debug)]
-pub struct Args {
-    pub infile: IOType,
-    pub sksa: IOType,
-    pub virage2: IOType,
-    pub bootrom: IOType,
-    pub outfile: IOType,
+#[derive(clap::Args, Debug)]
+struct VerifyCli {
+    /// Input SKSA
+    #[arg(short, long)]
+    sksa: String,
+
+    #[command(flatten)]
+    keys: KeyCli,
+
+    #[command(flatten)]
+    sk_size: SkSizeCli,
 }
 
-impl From<Cli> for Args {
-    fn from(value: Cli) -> Self {
-        fn replace_extension_or(orig: &Path, replace: &[&str], with: &str) -> PathBuf {
-            match orig.extension() {
-                Some(_)
-                    if replace.iter().map(OsString::from).any(|s| {
-                        s.to_ascii_lowercase() == orig.extension().unwrap().to_ascii_lowercase()
-                    }) =>
-                {
-                    orig.with_extension(with)
-                }
-                None => orig.with_extension(with),
-                _ => {
-                    let mut s = orig.as_os_str().to_owned();
-                    s.push(format!(".{with}"));
-                    s.into()
-                }
-            }
-        }
+#[derive(clap::Args, Debug)]
+struct ValidateCli {
+    /// Built SKSA to check
+    #[arg(short, long)]
+    sksa: String,
 
-        let infile = IOType::input(value.infile);
-        let sksa = IOType::input(value.sksa);
-        let virage2 = IOType::input(value.virage2);
-        let bootrom = IOType::input(value.bootrom);
-        let outfile = match value.outfile {
-            Some(f) => IOType::output(f),
-            None => infile.derive_output(|p| replace_extension_or(p, &["bin"], "sksa")),
-        };
+    #[command(flatten)]
+    keys: KeyCli,
 
-        Self {
-            infile,
-            sksa,
-            virage2,
-            bootrom,
-            outfile,
-        }
+    #[command(flatten)]
+    options: BuildOptionsCli,
+
+    #[command(flatten)]
+    layout: LayoutCli,
+
+    /// Payload the SKSA is claimed to have been built from; "-" for stdin
+    payload: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct InfoCli {
+    #[command(flatten)]
+    sksa: SksaPathCli,
+
+    #[command(flatten)]
+    sk_size: SkSizeCli,
+}
+
+#[derive(clap::Args, Debug)]
+struct ListCli {
+    #[command(flatten)]
+    sksa: SksaPathCli,
+
+    #[command(flatten)]
+    sk_size: SkSizeCli,
+}
+
+/// An SKSA given either with --sksa or positionally; there's no default, so a missing SKSA is a
+/// usage error rather than a silent read from stdin
+#[derive(clap::Args, Debug)]
+struct SksaPathCli {
+    /// Input SKSA; "-" for stdin
+    #[arg(short, long, required_unless_present = "sksa_path")]
+    sksa: Option<String>,
+
+    /// Input SKSA, as an alternative to --sksa
+    #[arg(value_name = "SKSA", conflicts_with = "sksa")]
+    sksa_path: Option<String>,
+}
+
+impl From<SksaPathCli> for IOType {
+    fn from(value: SksaPathCli) -> Self {
+        // one of the two is always present: clap enforces it, or a --config default fills in --sksa
+        IOType::input(value.sksa_path.or(value.sksa).unwrap_or_default())
     }
 }
 
-pub fn parse_args() -> Args {
-    Cli::parse().into()
+#[derive(clap::Args, Debug)]
+struct KeysCli {
+    /// Input SKSA
+    #[arg(short, long)]
+    sksa: String,
+
+    #[command(flatten)]
+    keys: KeyCli,
+
+    #[command(flatten)]
+    sk_size: SkSizeCli,
+
+    /// Common IV (32 hex digits) to derive the SA1 key with, instead of the one in the info block
+    #[arg(long, value_parser = parse_hex_array::<16>)]
+    common_cmd_iv: Option<BbAesIv>,
+
+    /// Acknowledge that secret key material will be printed
+    #[arg(long)]
+    dump_keys: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct TargetKeyCli {
+    /// Target Virage2 to re-encrypt for
+    #[arg(long)]
+    target_virage2: String,
+
+    /// Target bootrom (used for key derivation)
+    #[arg(long, required_unless_present_any = ["target_sk_hash", "target_no_verify"])]
+    target_bootrom: Option<String>,
+
+    /// Expected plaintext SK hash for the target Virage2, in hex
+    #[arg(long, value_parser = parse_hex_array::<20>, conflicts_with = "target_bootrom")]
+    target_sk_hash: Option<BbShaHash>,
+
+    /// Skip SK verification against the target Virage2
+    #[arg(long, conflicts_with_all = ["target_bootrom", "target_sk_hash"])]
+    target_no_verify: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct RecryptCli {
+    /// Input BBBS SKSA; "-" for stdin
+    #[arg(default_value_t = String::from("-"))]
+    infile: String,
+
+    #[command(flatten)]
+    keys: KeyCli,
+
+    #[command(flatten)]
+    sk_size: SkSizeCli,
+
+    #[command(flatten)]
+    target_keys: TargetKeyCli,
+
+    /// Which SA to re-encrypt (1 for SA1, 2 for SA2, ...); the other SAs are kept as they are
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    sa_index: u64,
+
+    /// IV the SA was encrypted with; the re-encrypted SA takes its IV from the same place
+    #[arg(long, value_enum, default_value_t = IvSource::Header)]
+    sa1_iv_from: IvSource,
+
+    /// How the SA was encrypted; it's re-encrypted the same way
+    #[arg(long, value_enum, default_value_t = CipherMode::Cbc)]
+    cipher_mode: CipherMode,
+
+    /// Common IV (32 hex digits) to derive the old and new SA keys with, instead of the one in the info block
+    #[arg(long, value_parser = parse_hex_array::<16>)]
+    common_cmd_iv: Option<BbAesIv>,
+
+    /// Allow the output to overwrite one of the inputs
+    #[arg(short, long)]
+    force: bool,
+
+    /// Output BBBS SKSA; "-" for stdout [default: <infile>.recrypt.sksa or -]
+    outfile: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct PatchCli {
+    /// Input BBBS SKSA; "-" for stdin
+    #[arg(default_value_t = String::from("-"))]
+    infile: String,
+
+    #[command(flatten)]
+    keys: KeyCli,
+
+    #[command(flatten)]
+    sk_size: SkSizeCli,
+
+    /// Bytes to write into the decrypted SA1, as <offset>:<bytes> with both in hex and the offset from the start of the SA1; may be given more than once
+    #[arg(long = "patch", value_name = "OFFSET:BYTES", value_parser = parse_patch, required = true)]
+    patches: Vec<(usize, Vec<u8>)>,
+
+    /// IV the SA1 was encrypted with
+    #[arg(long, value_enum, default_value_t = IvSource::Header)]
+    sa1_iv_from: IvSource,
+
+    /// How the SA1 was encrypted
+    #[arg(long, value_enum, default_value_t = CipherMode::Cbc)]
+    cipher_mode: CipherMode,
+
+    /// Allow the output to overwrite one of the inputs
+    #[arg(short, long)]
+    force: bool,
+
+    /// Output BBBS SKSA; "-" for stdout [default: <infile>.patched.sksa or -]
+    outfile: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct BatchCli {
+    /// Payloads, or directories of payloads
+    #[arg(required = true)]
+    payloads: Vec<PathBuf>,
+
+    /// Input SKSA
+    #[arg(short, long)]
+    sksa: String,
+
+    #[command(flatten)]
+    keys: KeyCli,
+
+    #[command(flatten)]
+    options: BuildOptionsCli,
+
+    #[command(flatten)]
+    layout: LayoutCli,
+
+    /// Directory to write the output SKSAs to [default: next to each payload]
+    #[arg(short, long)]
+    outdir: Option<PathBuf>,
+
+    /// Extension to give each output SKSA
+    #[arg(long, default_value = "sksa")]
+    out_ext: String,
+
+    /// Allow an output to overwrite one of the inputs
+    #[arg(short, long)]
+    force: bool,
+
+    /// Fail instead of warning when the rebuilt SA would share its key and IV with another SA
+    #[arg(long)]
+    strict: bool,
+}
+
+/// Virage2 fields, from the command line or a JSON file; missing fields are zero
+#[derive(clap::Args, Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Virage2Fields {
+    /// Plaintext SK hash, in hex
+    #[arg(long, value_parser = parse_hex_array::<20>)]
+    #[serde(default, deserialize_with = "de_hex")]
+    pub sk_hash: Option<BbShaHash>,
+
+    /// ROM patch words (JSON only)
+    #[arg(skip)]
+    pub rom_patch: Option<[u32; 16]>,
+
+    /// ECC public key, in hex
+    #[arg(long, value_parser = parse_hex_array::<64>)]
+    #[serde(default, deserialize_with = "de_hex")]
+    pub pub_key: Option<BbEccPublicKey>,
+
+    /// Console ID, in hex
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub bbid: Option<u32>,
+
+    /// ECC private key, in hex
+    #[arg(long, value_parser = parse_hex_array::<32>)]
+    #[serde(default, deserialize_with = "de_hex")]
+    pub priv_key: Option<BbEccPrivateKey>,
+
+    /// Boot app key, in hex
+    #[arg(long, value_parser = parse_hex_array::<16>)]
+    #[serde(default, deserialize_with = "de_hex")]
+    pub boot_app_key: Option<BbAesKey>,
+
+    /// Recrypt list key, in hex
+    #[arg(long, value_parser = parse_hex_array::<16>)]
+    #[serde(default, deserialize_with = "de_hex")]
+    pub recrypt_list_key: Option<BbAesKey>,
+
+    /// App state key, in hex
+    #[arg(long, value_parser = parse_hex_array::<16>)]
+    #[serde(default, deserialize_with = "de_hex")]
+    pub app_state_key: Option<BbAesKey>,
+
+    /// Self message key, in hex
+    #[arg(long, value_parser = parse_hex_array::<16>)]
+    #[serde(default, deserialize_with = "de_hex")]
+    pub self_msg_key: Option<BbAesKey>,
+
+    /// JTAG enable word, in hex
+    #[arg(long, value_parser = parse_hex_u32)]
+    pub jtag_enable: Option<u32>,
+}
+
+impl Virage2Fields {
+    /// Fills any fields missing from `self` from `other`
+    pub fn or(self, other: Self) -> Self {
+        Self {
+            sk_hash: self.sk_hash.or(other.sk_hash),
+            rom_patch: self.rom_patch.or(other.rom_patch),
+            pub_key: self.pub_key.or(other.pub_key),
+            bbid: self.bbid.or(other.bbid),
+            priv_key: self.priv_key.or(other.priv_key),
+            boot_app_key: self.boot_app_key.or(other.boot_app_key),
+            recrypt_list_key: self.recrypt_list_key.or(other.recrypt_list_key),
+            app_state_key: self.app_state_key.or(other.app_state_key),
+            self_msg_key: self.self_msg_key.or(other.self_msg_key),
+            jtag_enable: self.jtag_enable.or(other.jtag_enable),
+        }
+    }
+}
+
+fn de_hex<'de, D: Deserializer<'de>, const N: usize>(d: D) -> Result<Option<[u8; N]>, D::Error> {
+    let s = String::deserialize(d)?;
+    parse_hex_array(&s).map(Some).map_err(D::Error::custom)
+}
+
+#[derive(clap::Args, Debug)]
+struct MkVirage2Cli {
+    #[command(flatten)]
+    fields: Virage2Fields,
+
+    /// JSON file of fields (as hex strings, or numbers for bbid/jtag_enable); command-line fields take precedence
+    #[arg(long)]
+    json: Option<String>,
+
+    /// Output Virage2; "-" for stdout
+    outfile: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct BundleCli {
+    /// Input SKSA
+    #[arg(short, long)]
+    sksa: String,
+
+    /// Input Virage2
+    #[arg(short, long)]
+    virage2: String,
+
+    /// Input bootrom
+    #[arg(short, long)]
+    bootrom: Option<String>,
+
+    /// Output bundle; "-" for stdout
+    outfile: String,
+}
+
+#[derive(Debug)]
+pub struct Args {
+    pub verbose: bool,
+    pub quiet: bool,
+    pub command: Command,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Build(Box<BuildArgs>),
+    Extract(ExtractArgs),
+    Verify(VerifyArgs),
+    Validate(ValidateArgs),
+    Info(InfoArgs),
+    List(ListArgs),
+    Keys(KeysArgs),
+    Recrypt(RecryptArgs),
+    Patch(PatchArgs),
+    Batch(BatchArgs),
+    MkVirage2(MkVirage2Args),
+    Bundle(BundleArgs),
+}
+
+#[derive(Debug)]
+pub struct KeyArgs {
+    pub virage2: Option<IOType>,
+    pub virage2_offset: Option<usize>,
+    pub bootrom: Option<IOType>,
+    pub bootrom_offset: Option<usize>,
+    pub sk_hash: Option<BbShaHash>,
+    pub no_verify: bool,
+    pub expect_sk: Option<IOType>,
+    pub bundle: Option<IOType>,
+}
+
+impl KeyArgs {
+    pub fn inputs(&self) -> impl Iterator<Item = &IOType> {
+        self.virage2
+            .iter()
+            .chain(&self.bootrom)
+            .chain(&self.expect_sk)
+            .chain(&self.bundle)
+    }
+}
+
+/// Where the input SKSA comes from: a single file, or the SK and SA command block separately
+#[derive(Debug)]
+pub enum SksaInput {
+    Combined(IOType),
+    Parts { sk: IOType, cmd: IOType },
+    Bundle(IOType),
+}
+
+impl SksaInput {
+    pub fn inputs(&self) -> impl Iterator<Item = &IOType> {
+        match self {
+            Self::Combined(sksa) | Self::Bundle(sksa) => [Some(sksa), None],
+            Self::Parts { sk, cmd } => [Some(sk), Some(cmd)],
+        }
+        .into_iter()
+        .flatten()
+    }
+}
+
+#[derive(Debug)]
+pub struct LayoutArgs {
+    pub input_format: InputFormat,
+    /// Whether the entrypoint was given, so shouldn't be taken from an ELF payload
+    pub explicit_entrypoint: bool,
+    pub sa1_stub: Option<IOType>,
+    pub extra_payloads: Vec<(usize, IOType)>,
+}
+
+#[derive(Debug)]
+pub struct BuildArgs {
+    pub infile: IOType,
+    pub sksa: SksaInput,
+    pub keys: KeyArgs,
+    pub outfile: IOType,
+    pub options: BuildOptions,
+    pub print_sa1_hash: bool,
+    pub crc: bool,
+    pub print_padding: bool,
+    pub dump_plain_sa1: Option<IOType>,
+    pub output_hash_file: Option<IOType>,
+    pub json: Option<IOType>,
+    pub layout: LayoutArgs,
+    #[cfg(feature = "sign")]
+    pub sign_key: Option<IOType>,
+    pub sa1_key: Option<(BbAesKey, BbAesIv)>,
+    pub expect_sa1_hash: Option<BbShaHash>,
+    pub payload_sha1: Option<BbShaHash>,
+    pub ignore_sk_hash: bool,
+    pub max_payload: bool,
+    pub stdin_len: Option<usize>,
+    pub force: bool,
+    pub compare: Option<IOType>,
+    pub cbc_common_prefix: Option<IOType>,
+    pub dry_run: bool,
+    pub stats: bool,
+    pub verify_write: bool,
+    pub manifest: Option<IOType>,
+    pub strict: bool,
+    pub dump_all: Option<PathBuf>,
+    pub dump_keys: bool,
+    pub mmap: bool,
+}
+
+#[derive(Debug)]
+pub struct ExtractArgs {
+    pub infile: IOType,
+    pub keys: KeyArgs,
+    pub sk_size: usize,
+    pub header_size: usize,
+    pub payload_offset: usize,
+    pub trim: bool,
+    pub min_size: usize,
+    pub trim_aligned: bool,
+    pub sa1_iv_from: IvSource,
+    pub cipher_mode: CipherMode,
+    pub force: bool,
+    pub outfile: IOType,
+}
+
+#[derive(Debug)]
+pub struct VerifyArgs {
+    pub sksa: IOType,
+    pub keys: KeyArgs,
+    pub sk_size: usize,
+}
+
+#[derive(Debug)]
+pub struct ValidateArgs {
+    pub sksa: IOType,
+    pub keys: KeyArgs,
+    pub options: BuildOptions,
+    pub layout: LayoutArgs,
+    pub payload: IOType,
+}
+
+#[derive(Debug)]
+pub struct InfoArgs {
+    pub sksa: IOType,
+    pub sk_size: usize,
+}
+
+#[derive(Debug)]
+pub struct ListArgs {
+    pub sksa: IOType,
+    pub sk_size: usize,
+}
+
+#[derive(Debug)]
+pub struct KeysArgs {
+    pub sksa: IOType,
+    pub keys: KeyArgs,
+    pub sk_size: usize,
+    pub common_cmd_iv: Option<BbAesIv>,
+    pub dump_keys: bool,
+}
+
+#[derive(Debug)]
+pub struct RecryptArgs {
+    pub infile: IOType,
+    pub keys: KeyArgs,
+    pub sk_size: usize,
+    pub target_keys: KeyArgs,
+    pub sa_index: usize,
+    pub sa1_iv_from: IvSource,
+    pub cipher_mode: CipherMode,
+    pub common_cmd_iv: Option<BbAesIv>,
+    pub force: bool,
+    pub outfile: IOType,
+}
+
+#[derive(Debug)]
+pub struct PatchArgs {
+    pub infile: IOType,
+    pub keys: KeyArgs,
+    pub sk_size: usize,
+    pub patches: Vec<(usize, Vec<u8>)>,
+    pub sa1_iv_from: IvSource,
+    pub cipher_mode: CipherMode,
+    pub force: bool,
+    pub outfile: IOType,
+}
+
+#[derive(Debug)]
+pub struct BatchArgs {
+    pub payloads: Vec<PathBuf>,
+    pub sksa: IOType,
+    pub keys: KeyArgs,
+    pub options: BuildOptions,
+    pub layout: LayoutArgs,
+    pub outdir: Option<PathBuf>,
+    pub out_ext: String,
+    pub force: bool,
+    pub strict: bool,
+}
+
+#[derive(Debug)]
+pub struct MkVirage2Args {
+    pub fields: Virage2Fields,
+    pub json: Option<IOType>,
+    pub outfile: IOType,
+}
+
+#[derive(Debug)]
+pub struct BundleArgs {
+    pub sksa: IOType,
+    pub virage2: IOType,
+    pub bootrom: Option<IOType>,
+    pub outfile: IOType,
+}
+
+/// Replace `orig`'s extension with `with` if it is one of `replace` (or missing), otherwise append `.with`
+pub fn replace_extension_or(orig: &Path, replace: &[&str], with: &str) -> PathBuf {
+    match orig.extension() {
+        Some(ext) if replace.iter().any(|s| ext.eq_ignore_ascii_case(s)) => {
+            orig.with_extension(with)
+        }
+        None if orig.file_name().is_some() => orig.with_extension(with),
+        _ => {
+            let mut s = orig.as_os_str().to_owned();
+            s.push(format!(".{with}"));
+            s.into()
+        }
+    }
+}
+
+impl From<SkSizeCli> for usize {
+    fn from(value: SkSizeCli) -> Self {
+        value.sk_size
+    }
+}
+
+impl From<KeyCli> for KeyArgs {
+    fn from(value: KeyCli) -> Self {
+        Self {
+            virage2: value.virage2.map(IOType::input),
+            virage2_offset: value.virage2_offset,
+            bootrom: value.bootrom.map(IOType::input),
+            bootrom_offset: value.bootrom_offset,
+            sk_hash: value.sk_hash,
+            no_verify: value.no_verify,
+            expect_sk: value.expect_sk.map(IOType::input),
+            bundle: value.bundle.map(IOType::input),
+        }
+    }
+}
+
+impl From<BuildOptionsCli> for BuildOptions {
+    fn from(value: BuildOptionsCli) -> Self {
+        let defaults = BuildOptions::default();
+        Self {
+            entrypoint: value.entrypoint.unwrap_or(defaults.entrypoint),
+            endian: value.endian,
+            compress: value.compress && !value.no_compress,
+            check_roundtrip: value.check_roundtrip,
+            sa1_only: value.sa1_only,
+            pad_byte: value.pad_byte,
+            sa_index: value.sa_index as usize,
+            sk_size: value.sk_size.into(),
+            header_size: value.header_size,
+            payload_offset: value.payload_offset,
+            allow_prebuilt: value.allow_prebuilt,
+            allow_empty: value.allow_empty,
+            preserve_tail: value.preserve_tail,
+            sa1_iv_from: value.sa1_iv_from,
+            cipher_mode: value.cipher_mode,
+            common_cmd_iv: value.common_cmd_iv,
+            progress: value.progress,
+            ..defaults
+        }
+    }
+}
+
+impl LayoutCli {
+    fn into_args(self, options: &BuildOptionsCli) -> LayoutArgs {
+        LayoutArgs {
+            input_format: self.input_format,
+            explicit_entrypoint: options.entrypoint.is_some() && !options.config_entrypoint,
+            sa1_stub: self.sa1_stub.map(IOType::input),
+            extra_payloads: self
+                .extra_payload
+                .into_iter()
+                .map(|(offset, file)| (offset, IOType::input(file)))
+                .collect(),
+        }
+    }
+}
+
+impl From<BuildCli> for BuildArgs {
+    fn from(value: BuildCli) -> Self {
+        let infile = IOType::input(value.infile);
+        let sksa = match value.sk.zip(value.cmd) {
+            Some((sk, cmd)) => SksaInput::Parts {
+                sk: IOType::input(sk),
+                cmd: IOType::input(cmd),
+            },
+            None => match (value.sksa, &value.keys.bundle) {
+                (Some(sksa), _) => SksaInput::Combined(IOType::input(sksa)),
+                (None, bundle) => {
+                    SksaInput::Bundle(IOType::input(bundle.clone().unwrap_or_default()))
+                }
+            },
+        };
+        let compare_only = value.compare.is_some() && value.outfile.is_none();
+        let outfile = match value.outfile {
+            Some(f) => IOType::output(f),
+            None => infile.derive_output(|p| {
+                let derived =
+                    replace_extension_or(p, &["bin"], value.out_ext.trim_start_matches('.'));
+                match (&value.outdir, derived.file_name()) {
+                    (Some(dir), Some(name)) => dir.join(name),
+                    _ => derived,
+                }
+            }),
+        };
+
+        let output_hash_file = value.output_hash_file.map(|f| match f {
+            Some(f) => IOType::output(f),
+            None => match &outfile {
+                IOType::File(p) => {
+                    let mut s = p.as_os_str().to_owned();
+                    s.push(".sha1");
+                    IOType::File(s.into())
+                }
+                _ => IOType::Stderr,
+            },
+        });
+
+        let json = value.json.map(|f| match f {
+            Some(f) => IOType::output(f),
+            None => match &outfile {
+                IOType::Stdout => IOType::Stderr,
+                _ => IOType::Stdout,
+            },
+        });
+
+        Self {
+            infile,
+            sksa,
+            keys: value.keys.into(),
+            outfile,
+            layout: value.layout.into_args(&value.options),
+            options: value.options.into(),
+            print_sa1_hash: value.print_sa1_hash,
+            crc: value.crc,
+            print_padding: value.print_padding,
+            dump_plain_sa1: value.dump_plain_sa1.map(IOType::output),
+            output_hash_file,
+            json,
+            #[cfg(feature = "sign")]
+            sign_key: value.sign_key.map(IOType::input),
+            sa1_key: value.sa1_key.zip(value.sa1_iv),
+            expect_sa1_hash: value.expect_sa1_hash,
+            payload_sha1: value.payload_sha1,
+            ignore_sk_hash: value.ignore_sk_hash,
+            max_payload: value.max_payload,
+            stdin_len: value.stdin_len,
+            force: value.force,
+            dry_run: value.dry_run || compare_only,
+            stats: value.stats,
+            verify_write: value.verify_write,
+            manifest: value.manifest.map(IOType::output),
+            strict: value.strict,
+            dump_all: value.dump_all,
+            dump_keys: value.dump_keys,
+            compare: value.compare.map(IOType::input),
+            cbc_common_prefix: value.cbc_common_prefix.map(IOType::input),
+            mmap: value.mmap,
+        }
+    }
+}
+
+impl From<ExtractCli> for ExtractArgs {
+    fn from(value: ExtractCli) -> Self {
+        let infile = IOType::input(value.infile);
+        let outfile = match value.outfile {
+            Some(f) => IOType::output(f),
+            None => infile.derive_output(|p| replace_extension_or(p, &["sksa"], "bin")),
+        };
+
+        Self {
+            infile,
+            keys: value.keys.into(),
+            sk_size: value.sk_size.into(),
+            header_size: value.header_size,
+            payload_offset: value.payload_offset,
+            trim: value.trim,
+            min_size: value.min_size,
+            trim_aligned: value.trim_aligned,
+            sa1_iv_from: value.sa1_iv_from,
+            cipher_mode: value.cipher_mode,
+            force: value.force,
+            outfile,
+        }
+    }
+}
+
+impl From<VerifyCli> for VerifyArgs {
+    fn from(value: VerifyCli) -> Self {
+        Self {
+            sksa: IOType::input(value.sksa),
+            sk_size: value.sk_size.into(),
+            keys: value.keys.into(),
+        }
+    }
+}
+
+impl From<ValidateCli> for ValidateArgs {
+    fn from(value: ValidateCli) -> Self {
+        Self {
+            sksa: IOType::input(value.sksa),
+            keys: value.keys.into(),
+            layout: value.layout.into_args(&value.options),
+            options: value.options.into(),
+            payload: IOType::input(value.payload),
+        }
+    }
+}
+
+impl From<InfoCli> for InfoArgs {
+    fn from(value: InfoCli) -> Self {
+        Self {
+            sksa: value.sksa.into(),
+            sk_size: value.sk_size.into(),
+        }
+    }
+}
+
+impl From<ListCli> for ListArgs {
+    fn from(value: ListCli) -> Self {
+        Self {
+            sksa: value.sksa.into(),
+            sk_size: value.sk_size.into(),
+        }
+    }
+}
+
+impl From<KeysCli> for KeysArgs {
+    fn from(value: KeysCli) -> Self {
+        Self {
+            sksa: IOType::input(value.sksa),
+            sk_size: value.sk_size.into(),
+            keys: value.keys.into(),
+            common_cmd_iv: value.common_cmd_iv,
+            dump_keys: value.dump_keys,
+        }
+    }
+}
+
+impl CliCommand {
+    fn options_mut(&mut self) -> Option<&mut BuildOptionsCli> {
+        match self {
+            Self::Build(b) => Some(&mut b.options),
+            Self::Validate(v) => Some(&mut v.options),
+            Self::Batch(b) => Some(&mut b.options),
+            _ => None,
+        }
+    }
+}
+
+impl From<CliCommand> for Command {
+    fn from(value: CliCommand) -> Self {
+        match value {
+            CliCommand::Build(b) => Self::Build(Box::new((*b).into())),
+            CliCommand::Extract(e) => Self::Extract(e.into()),
+            CliCommand::Verify(v) => Self::Verify(v.into()),
+            CliCommand::Validate(v) => Self::Validate(v.into()),
+            CliCommand::Info(i) => Self::Info(i.into()),
+            CliCommand::List(l) => Self::List(l.into()),
+            CliCommand::Keys(k) => Self::Keys(k.into()),
+            CliCommand::Recrypt(r) => Self::Recrypt(r.into()),
+            CliCommand::Patch(p) => Self::Patch(p.into()),
+            CliCommand::Batch(b) => Self::Batch(b.into()),
+            CliCommand::MkVirage2(m) => Self::MkVirage2(m.into()),
+            CliCommand::Bundle(b) => Self::Bundle(b.into()),
+        }
+    }
+}
+
+impl From<TargetKeyCli> for KeyArgs {
+    fn from(value: TargetKeyCli) -> Self {
+        Self {
+            virage2: Some(IOType::input(value.target_virage2)),
+            virage2_offset: None,
+            bootrom: value.target_bootrom.map(IOType::input),
+            bootrom_offset: None,
+            sk_hash: value.target_sk_hash,
+            no_verify: value.target_no_verify,
+            expect_sk: None,
+            bundle: None,
+        }
+    }
+}
+
+impl From<RecryptCli> for RecryptArgs {
+    fn from(value: RecryptCli) -> Self {
+        let infile = IOType::input(value.infile);
+        let outfile = match value.outfile {
+            Some(f) => IOType::output(f),
+            None => infile.derive_output(|p| replace_extension_or(p, &["sksa"], "recrypt.sksa")),
+        };
+
+        Self {
+            infile,
+            keys: value.keys.into(),
+            sk_size: value.sk_size.into(),
+            target_keys: value.target_keys.into(),
+            sa_index: value.sa_index as usize,
+            sa1_iv_from: value.sa1_iv_from,
+            cipher_mode: value.cipher_mode,
+            common_cmd_iv: value.common_cmd_iv,
+            force: value.force,
+            outfile,
+        }
+    }
+}
+
+impl From<PatchCli> for PatchArgs {
+    fn from(value: PatchCli) -> Self {
+        let infile = IOType::input(value.infile);
+        let outfile = match value.outfile {
+            Some(f) => IOType::output(f),
+            None => infile.derive_output(|p| replace_extension_or(p, &["sksa"], "patched.sksa")),
+        };
+
+        Self {
+            infile,
+            keys: value.keys.into(),
+            sk_size: value.sk_size.into(),
+            patches: value.patches,
+            sa1_iv_from: value.sa1_iv_from,
+            cipher_mode: value.cipher_mode,
+            force: value.force,
+            outfile,
+        }
+    }
+}
+
+impl From<BatchCli> for BatchArgs {
+    fn from(value: BatchCli) -> Self {
+        Self {
+            payloads: value.payloads,
+            sksa: IOType::input(value.sksa),
+            keys: value.keys.into(),
+            layout: value.layout.into_args(&value.options),
+            options: value.options.into(),
+            outdir: value.outdir,
+            out_ext: value.out_ext.trim_start_matches('.').to_owned(),
+            force: value.force,
+            strict: value.strict,
+        }
+    }
+}
+
+impl From<MkVirage2Cli> for MkVirage2Args {
+    fn from(value: MkVirage2Cli) -> Self {
+        Self {
+            fields: value.fields,
+            json: value.json.map(IOType::input),
+            outfile: IOType::output(value.outfile),
+        }
+    }
+}
+
+impl From<BundleCli> for BundleArgs {
+    fn from(value: BundleCli) -> Self {
+        Self {
+            sksa: IOType::input(value.sksa),
+            virage2: IOType::input(value.virage2),
+            bootrom: value.bootrom.map(IOType::input),
+            outfile: IOType::output(value.outfile),
+        }
+    }
+}
+
+impl From<Cli> for Args {
+    fn from(value: Cli) -> Self {
+        Self {
+            verbose: value.verbose,
+            quiet: value.quiet,
+            command: value.command.into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    sksa: Option<String>,
+    virage2: Option<String>,
+    bootrom: Option<String>,
+    entrypoint: Option<u32>,
+    compress: Option<bool>,
+}
+
+impl Config {
+    /// The argument ids the config sets, with their values; flags that are set have no value
+    fn values(&self) -> Vec<(&'static str, Option<String>)> {
+        let mut rv: Vec<_> = [
+            ("sksa", self.sksa.clone()),
+            ("virage2", self.virage2.clone()),
+            ("bootrom", self.bootrom.clone()),
+            ("entrypoint", self.entrypoint.map(|e| format!("{e:X}"))),
+        ]
+        .into_iter()
+        .filter_map(|(id, v)| v.map(|v| (id, Some(v))))
+        .collect();
+
+        if self.compress == Some(true) {
+            rv.push(("compress", None));
+        }
+
+        rv
+    }
+}
+
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Stops requiring the arguments the config sets, so the command line can leave them out
+fn relax_config_args(mut command: clap::Command, config: &Config) -> clap::Command {
+    let names: Vec<String> = command
+        .get_subcommands()
+        .map(|c| c.get_name().to_owned())
+        .collect();
+
+    for (id, _) in config.values() {
+        for name in &names {
+            command = command.mut_subcommand(name, |sub| {
+                if sub.get_arguments().any(|a| a.get_id() == id) {
+                    sub.mut_arg(id, |a| {
+                        a.required(false)
+                            .required_unless_present(clap::builder::Resettable::Reset)
+                    })
+                } else {
+                    sub
+                }
+            });
+        }
+    }
+
+    command
+}
+
+/// Returns the arguments that apply the config's values to the parsed subcommand, skipping any the
+/// command line already gave (or gave the negation of, or something conflicting with, such as a
+/// positional alias)
+fn config_args(command: &clap::Command, matches: &ArgMatches, config: &Config) -> Vec<OsString> {
+    let Some((name, matches)) = matches.subcommand() else {
+        return vec![];
+    };
+    let Some(sub) = command.find_subcommand(name) else {
+        return vec![];
+    };
+    let has_arg = |id: &str| sub.get_arguments().any(|a| a.get_id() == id);
+    let given =
+        |id: &str| has_arg(id) && matches.value_source(id) == Some(ValueSource::CommandLine);
+
+    let mut rv = vec![];
+    for (id, value) in config.values() {
+        let Some(arg) = sub.get_arguments().find(|a| a.get_id() == id) else {
+            continue;
+        };
+        // conflicts are only listed on the arg that declares them, so check both directions
+        let conflicts = |other: &clap::Arg| {
+            sub.get_arg_conflicts_with(arg)
+                .iter()
+                .any(|a| a.get_id() == other.get_id())
+                || sub
+                    .get_arg_conflicts_with(other)
+                    .iter()
+                    .any(|a| a.get_id() == arg.get_id())
+        };
+        if given(id)
+            || given(&format!("no_{id}"))
+            || sub
+                .get_arguments()
+                .any(|a| conflicts(a) && given(a.get_id().as_str()))
+        {
+            continue;
+        }
+
+        let long = arg.get_long().expect("config keys are all long options");
+        rv.push(OsString::from(format!("--{long}")));
+        rv.extend(value.map(OsString::from));
+    }
+
+    rv
+}
+
+/// Index of the subcommand name in `args`; the only global option that takes a value is --config
+fn subcommand_index(args: &[OsString]) -> Option<usize> {
+    let mut i = 1;
+    while let Some(arg) = args.get(i) {
+        if arg == "--config" {
+            i += 1;
+        } else if !arg.to_string_lossy().starts_with('-') {
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+pub fn parse_args() -> Args {
+    parse_args_from(std::env::args_os().collect())
+}
+
+/// Like [`parse_args`], but parses `args` (including the program name) instead of the process's
+pub fn parse_args_from(mut args: Vec<OsString>) -> Args {
+    let mut command = Cli::command();
+
+    let config = config_path(&args).map(|path| {
+        read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|s| toml::from_str::<Config>(&s).map_err(|e| e.to_string()))
+            .unwrap_or_else(|e| {
+                command
+                    .error(
+                        clap::error::ErrorKind::Io,
+                        format!("failed to read config {}: {e}", path.display()),
+                    )
+                    .exit()
+            })
+    });
+
+    if let Some(config) = &config {
+        command = relax_config_args(command, config);
+    }
+
+    let mut matches = command.clone().get_matches_from(&args);
+
+    let mut config_entrypoint = false;
+    if let Some(config) = &config {
+        let extra = config_args(&command, &matches, config);
+        if let (false, Some(i)) = (extra.is_empty(), subcommand_index(&args)) {
+            config_entrypoint = extra.iter().any(|a| a == "--entrypoint");
+            args.splice(i + 1..i + 1, extra);
+            matches = command.get_matches_from(&args);
+        }
+    }
+
+    let mut cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(options) = cli.command.options_mut() {
+        options.config_entrypoint = config_entrypoint;
+    }
+    cli.into()
 }
@@ -0,0 +1,183 @@
+use bb::{BbAesIv, BbShaHash};
+
+use std::sync::Arc;
+
+use crate::crypto::Sha1Backend;
+use crate::{
+    build_sksa_with_options, BBBSError, BuildOptions, BuildStage, CipherMode, Endian, IvSource,
+    SkCheck, StageHook,
+};
+
+#[derive(Debug, Default)]
+pub struct SksaBuilder {
+    payload: Option<Vec<u8>>,
+    sksa: Option<Vec<u8>>,
+    virage2: Option<Vec<u8>>,
+    bootrom: Option<Vec<u8>>,
+    sk_hash: Option<BbShaHash>,
+    no_verify: bool,
+    options: BuildOptions,
+}
+
+impl SksaBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    pub fn sksa(mut self, sksa: Vec<u8>) -> Self {
+        self.sksa = Some(sksa);
+        self
+    }
+
+    pub fn virage2(mut self, virage2: Vec<u8>) -> Self {
+        self.virage2 = Some(virage2);
+        self
+    }
+
+    pub fn bootrom(mut self, bootrom: Vec<u8>) -> Self {
+        self.bootrom = Some(bootrom);
+        self
+    }
+
+    pub fn sk_hash(mut self, sk_hash: BbShaHash) -> Self {
+        self.sk_hash = Some(sk_hash);
+        self
+    }
+
+    pub fn no_verify(mut self, no_verify: bool) -> Self {
+        self.no_verify = no_verify;
+        self
+    }
+
+    pub fn entrypoint(mut self, entrypoint: u32) -> Self {
+        self.options.entrypoint = entrypoint;
+        self
+    }
+
+    pub fn endian(mut self, endian: Endian) -> Self {
+        self.options.endian = endian;
+        self
+    }
+
+    pub fn compress(mut self, compress: bool) -> Self {
+        self.options.compress = compress;
+        self
+    }
+
+    pub fn check_roundtrip(mut self, check_roundtrip: bool) -> Self {
+        self.options.check_roundtrip = check_roundtrip;
+        self
+    }
+
+    pub fn sa1_only(mut self, sa1_only: bool) -> Self {
+        self.options.sa1_only = sa1_only;
+        self
+    }
+
+    pub fn pad_byte(mut self, pad_byte: u8) -> Self {
+        self.options.pad_byte = pad_byte;
+        self
+    }
+
+    pub fn sa_index(mut self, sa_index: usize) -> Self {
+        self.options.sa_index = sa_index;
+        self
+    }
+
+    pub fn sk_size(mut self, sk_size: usize) -> Self {
+        self.options.sk_size = sk_size;
+        self
+    }
+
+    pub fn header_size(mut self, header_size: usize) -> Self {
+        self.options.header_size = header_size;
+        self
+    }
+
+    pub fn payload_offset(mut self, payload_offset: usize) -> Self {
+        self.options.payload_offset = payload_offset;
+        self
+    }
+
+    pub fn allow_prebuilt(mut self, allow_prebuilt: bool) -> Self {
+        self.options.allow_prebuilt = allow_prebuilt;
+        self
+    }
+
+    pub fn allow_empty(mut self, allow_empty: bool) -> Self {
+        self.options.allow_empty = allow_empty;
+        self
+    }
+
+    pub fn preserve_tail(mut self, preserve_tail: bool) -> Self {
+        self.options.preserve_tail = preserve_tail;
+        self
+    }
+
+    pub fn sa1_iv_from(mut self, sa1_iv_from: IvSource) -> Self {
+        self.options.sa1_iv_from = sa1_iv_from;
+        self
+    }
+
+    /// Calls `hook` as the build enters each [`BuildStage`]
+    pub fn on_stage(mut self, hook: impl Fn(BuildStage) + Send + Sync + 'static) -> Self {
+        self.options.on_stage = Some(StageHook(Arc::new(hook)));
+        self
+    }
+
+    pub fn cipher_mode(mut self, cipher_mode: CipherMode) -> Self {
+        self.options.cipher_mode = cipher_mode;
+        self
+    }
+
+    pub fn common_cmd_iv(mut self, common_cmd_iv: BbAesIv) -> Self {
+        self.options.common_cmd_iv = Some(common_cmd_iv);
+        self
+    }
+
+    pub fn stub(mut self, stub: Vec<u8>) -> Self {
+        self.options.stub = stub;
+        self
+    }
+
+    pub fn extra_payload(mut self, offset: usize, data: Vec<u8>) -> Self {
+        self.options.extra_payloads.push((offset, data));
+        self
+    }
+
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.options.progress = progress;
+        self
+    }
+
+    pub fn sha1_backend(mut self, backend: impl Sha1Backend + Send + Sync + 'static) -> Self {
+        self.options.sha1_backend = Some(Arc::new(backend));
+        self
+    }
+
+    #[cfg(feature = "sign")]
+    pub fn sign_key(mut self, key: rsa::RsaPrivateKey) -> Self {
+        self.options.sign_key = Some(Arc::new(key));
+        self
+    }
+
+    pub fn build(self) -> Result<Vec<u8>, BBBSError> {
+        let payload = self.payload.ok_or(BBBSError::MissingInput("payload"))?;
+        let sksa = self.sksa.ok_or(BBBSError::MissingInput("SKSA"))?;
+        let virage2 = self.virage2.ok_or(BBBSError::MissingInput("Virage2"))?;
+
+        let sk_check = match (self.no_verify, self.sk_hash, &self.bootrom) {
+            (true, _, _) => SkCheck::Skip,
+            (false, Some(sk_hash), _) => SkCheck::Hash(sk_hash),
+            (false, None, Some(bootrom)) => SkCheck::Bootrom(bootrom),
+            (false, None, None) => return Err(BBBSError::MissingInput("bootrom")),
+        };
+
+        build_sksa_with_options(payload, &sksa, &virage2, sk_check, &self.options).map(|r| r.output)
+    }
+}
@@ -0,0 +1,99 @@
+use crate::BBBSError;
+
+/// Per-console material (SKSA, Virage2 and optionally a bootrom) in a single file
+///
+/// The file starts with [`Bundle::MAGIC`] and a big-endian `u32` entry count, followed by that many
+/// entries of a 4-byte tag and big-endian `u32` offset and size, each pointing at a region of the
+/// file. Tags that aren't recognised are ignored.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Bundle {
+    pub sksa: Option<Vec<u8>>,
+    pub virage2: Option<Vec<u8>>,
+    pub bootrom: Option<Vec<u8>>,
+}
+
+impl Bundle {
+    pub const MAGIC: [u8; 8] = *b"BBBSBNDL";
+
+    const SKSA_TAG: [u8; 4] = *b"SKSA";
+    const VIRAGE2_TAG: [u8; 4] = *b"VRG2";
+    const BOOTROM_TAG: [u8; 4] = *b"BROM";
+
+    const HEADER_SIZE: usize = Self::MAGIC.len() + 4;
+    const ENTRY_SIZE: usize = 12;
+
+    fn regions(&self) -> Vec<([u8; 4], &[u8])> {
+        [
+            (Self::SKSA_TAG, &self.sksa),
+            (Self::VIRAGE2_TAG, &self.virage2),
+            (Self::BOOTROM_TAG, &self.bootrom),
+        ]
+        .into_iter()
+        .filter_map(|(tag, data)| data.as_deref().map(|d| (tag, d)))
+        .collect()
+    }
+
+    pub fn to_buf(&self) -> Vec<u8> {
+        let regions = self.regions();
+
+        let mut rv = Self::MAGIC.to_vec();
+        rv.extend((regions.len() as u32).to_be_bytes());
+
+        let mut offset = Self::HEADER_SIZE + regions.len() * Self::ENTRY_SIZE;
+        for (tag, data) in &regions {
+            rv.extend(tag);
+            rv.extend((offset as u32).to_be_bytes());
+            rv.extend((data.len() as u32).to_be_bytes());
+            offset += data.len();
+        }
+
+        for (_, data) in regions {
+            rv.extend(data);
+        }
+
+        rv
+    }
+
+    pub fn read_from_buf(buf: &[u8]) -> Result<Self, BBBSError> {
+        if buf.get(..Self::MAGIC.len()) != Some(&Self::MAGIC) {
+            return Err(BBBSError::InvalidBundle("bad magic"));
+        }
+
+        let count = read_u32(buf, Self::MAGIC.len())? as usize;
+
+        let mut rv = Self::default();
+        for i in 0..count {
+            let entry = Self::HEADER_SIZE + i * Self::ENTRY_SIZE;
+            let tag = buf
+                .get(entry..entry + 4)
+                .ok_or(BBBSError::InvalidBundle("entry table is truncated"))?;
+            let offset = read_u32(buf, entry + 4)? as usize;
+            let size = read_u32(buf, entry + 8)? as usize;
+
+            let data = buf
+                .get(offset..offset.saturating_add(size))
+                .ok_or(BBBSError::RegionOutOfBounds(
+                    offset,
+                    size,
+                    "bundle",
+                    buf.len(),
+                ))?
+                .to_vec();
+
+            match tag.try_into().unwrap() {
+                Self::SKSA_TAG => rv.sksa = Some(data),
+                Self::VIRAGE2_TAG => rv.virage2 = Some(data),
+                Self::BOOTROM_TAG => rv.bootrom = Some(data),
+                _ => {}
+            }
+        }
+
+        Ok(rv)
+    }
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32, BBBSError> {
+    buf.get(offset..offset + 4)
+        .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+        .ok_or(BBBSError::InvalidBundle("entry table is truncated"))
+}
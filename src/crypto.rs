@@ -0,0 +1,219 @@
+//! SK verification and SA1 key derivation, using only `core` so they can run without `std`; soft-aes
+//! needs `std`, so without it the `aes` crate is used for the block cipher instead
+
+#[cfg(not(feature = "std"))]
+use aes::cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit};
+#[cfg(not(feature = "std"))]
+use aes::Aes128;
+use sha1::{Digest, Sha1};
+#[cfg(feature = "std")]
+use soft_aes::aes::{aes_dec_cbc, aes_enc_block, aes_enc_cbc};
+use subtle::ConstantTimeEq;
+
+use core::fmt::{self, Display, Formatter};
+
+pub const AES_BLOCK_SIZE: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    NotBlockAligned(usize),
+    SKHashMismatch {
+        calculated: [u8; 20],
+        expected: [u8; 20],
+    },
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotBlockAligned(len) => write!(
+                f,
+                "length 0x{len:X} is not a multiple of the AES block size (0x{AES_BLOCK_SIZE:X})"
+            ),
+            Self::SKHashMismatch { .. } => write!(f, "SK hash does not match"),
+        }
+    }
+}
+
+fn check_aligned(data: &[u8]) -> Result<(), CryptoError> {
+    if !data.len().is_multiple_of(AES_BLOCK_SIZE) {
+        return Err(CryptoError::NotBlockAligned(data.len()));
+    }
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+pub fn cbc_encrypt_in_place(
+    data: &mut [u8],
+    key: &[u8; 16],
+    iv: &[u8; 16],
+) -> Result<(), CryptoError> {
+    check_aligned(data)?;
+
+    let encrypted = aes_enc_cbc(data, key, iv, None).expect("the data is block aligned");
+    data.copy_from_slice(&encrypted);
+
+    Ok(())
+}
+
+#[cfg(feature = "std")]
+pub fn cbc_decrypt_in_place(
+    data: &mut [u8],
+    key: &[u8; 16],
+    iv: &[u8; 16],
+) -> Result<(), CryptoError> {
+    check_aligned(data)?;
+
+    let decrypted = aes_dec_cbc(data, key, iv, None).expect("the data is block aligned");
+    data.copy_from_slice(&decrypted);
+
+    Ok(())
+}
+
+#[cfg(not(feature = "std"))]
+pub fn cbc_encrypt_in_place(
+    data: &mut [u8],
+    key: &[u8; 16],
+    iv: &[u8; 16],
+) -> Result<(), CryptoError> {
+    check_aligned(data)?;
+
+    let cipher = Aes128::new(key.into());
+    let mut prev = *iv;
+
+    for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+        block.iter_mut().zip(&prev).for_each(|(b, p)| *b ^= p);
+        cipher.encrypt_block(GenericArray::from_mut_slice(block));
+        prev.copy_from_slice(block);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(feature = "std"))]
+pub fn cbc_decrypt_in_place(
+    data: &mut [u8],
+    key: &[u8; 16],
+    iv: &[u8; 16],
+) -> Result<(), CryptoError> {
+    check_aligned(data)?;
+
+    let cipher = Aes128::new(key.into());
+    let mut prev = *iv;
+
+    for block in data.chunks_exact_mut(AES_BLOCK_SIZE) {
+        let mut next = [0; AES_BLOCK_SIZE];
+        next.copy_from_slice(block);
+        cipher.decrypt_block(GenericArray::from_mut_slice(block));
+        block.iter_mut().zip(&prev).for_each(|(b, p)| *b ^= p);
+        prev = next;
+    }
+
+    Ok(())
+}
+
+/// Encrypts one block with `key`, for CTR's keystream
+#[cfg(feature = "std")]
+fn encrypt_block(key: &[u8; 16]) -> impl Fn(&mut [u8; 16]) + '_ {
+    move |block| *block = aes_enc_block(block, key).expect("the key is 16 bytes")
+}
+
+/// Encrypts one block with `key`, for CTR's keystream
+#[cfg(not(feature = "std"))]
+fn encrypt_block(key: &[u8; 16]) -> impl Fn(&mut [u8; 16]) {
+    let cipher = Aes128::new(key.into());
+    move |block| cipher.encrypt_block(GenericArray::from_mut_slice(block))
+}
+
+/// Returns `counter` advanced by `blocks`, as a big-endian 128-bit integer
+pub fn ctr_advance(counter: &[u8; 16], blocks: usize) -> [u8; 16] {
+    u128::from_be_bytes(*counter)
+        .wrapping_add(blocks as u128)
+        .to_be_bytes()
+}
+
+/// Encrypts or decrypts `data` in place with AES-CTR, starting from `counter`; unlike CBC, any
+/// length is fine
+pub fn ctr_apply_in_place(data: &mut [u8], key: &[u8; 16], counter: &[u8; 16]) {
+    let encrypt = encrypt_block(key);
+
+    for (i, chunk) in data.chunks_mut(AES_BLOCK_SIZE).enumerate() {
+        let mut keystream = ctr_advance(counter, i);
+        encrypt(&mut keystream);
+        chunk.iter_mut().zip(&keystream).for_each(|(b, k)| *b ^= k);
+    }
+}
+
+/// A SHA-1 implementation, so that an accelerated (or deliberately broken) one can be swapped in
+pub trait Sha1Backend: fmt::Debug {
+    fn digest(&self, data: &[u8]) -> [u8; 20];
+}
+
+/// [`Sha1Backend`] using the `sha1` crate
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultSha1;
+
+impl Sha1Backend for DefaultSha1 {
+    fn digest(&self, data: &[u8]) -> [u8; 20] {
+        Sha1::digest(data).into()
+    }
+}
+
+/// Decrypts `sk` in place and returns its SHA-1 hash
+pub fn sk_hash(
+    sk: &mut [u8],
+    sk_key: &[u8; 16],
+    sk_iv: &[u8; 16],
+) -> Result<[u8; 20], CryptoError> {
+    sk_hash_with(sk, sk_key, sk_iv, &DefaultSha1)
+}
+
+/// Like [`sk_hash`], but hashes with `backend`
+pub fn sk_hash_with(
+    sk: &mut [u8],
+    sk_key: &[u8; 16],
+    sk_iv: &[u8; 16],
+    backend: &dyn Sha1Backend,
+) -> Result<[u8; 20], CryptoError> {
+    cbc_decrypt_in_place(sk, sk_key, sk_iv)?;
+    Ok(backend.digest(sk))
+}
+
+/// Decrypts `sk` in place and checks its hash against the one from the Virage2
+pub fn check_sk(
+    sk: &mut [u8],
+    sk_key: &[u8; 16],
+    sk_iv: &[u8; 16],
+    expected: &[u8; 20],
+) -> Result<(), CryptoError> {
+    check_sk_with(sk, sk_key, sk_iv, expected, &DefaultSha1)
+}
+
+/// Like [`check_sk`], but hashes with `backend`
+pub fn check_sk_with(
+    sk: &mut [u8],
+    sk_key: &[u8; 16],
+    sk_iv: &[u8; 16],
+    expected: &[u8; 20],
+    backend: &dyn Sha1Backend,
+) -> Result<(), CryptoError> {
+    let calculated = sk_hash_with(sk, sk_key, sk_iv, backend)?;
+    if !bool::from(calculated.ct_eq(expected)) {
+        return Err(CryptoError::SKHashMismatch {
+            calculated,
+            expected: *expected,
+        });
+    }
+    Ok(())
+}
+
+pub fn derive_sa1_key(
+    cmd_key: &[u8; 16],
+    boot_app_key: &[u8; 16],
+    common_cmd_iv: &[u8; 16],
+) -> [u8; 16] {
+    let mut key = *cmd_key;
+    cbc_decrypt_in_place(&mut key, boot_app_key, common_cmd_iv)
+        .expect("a key is exactly one AES block");
+    key
+}
@@ -0,0 +1,63 @@
+use object::{Object, ObjectSegment};
+
+use crate::BBBSError;
+
+/// Largest span of loadable segments to flatten; far more than any SA1 holds, but small enough that
+/// a stray segment can't make the flat image huge
+const MAX_FLAT_SIZE: u64 = 0x1000000;
+
+/// The loadable contents of an ELF, laid out as they would be in memory
+#[derive(Debug, PartialEq, Eq)]
+pub struct FlatElf {
+    pub data: Vec<u8>,
+    /// Virtual address of the first byte of `data`
+    pub base: u64,
+    pub entrypoint: u32,
+}
+
+/// Flattens the loadable segments of `elf` into one image starting at the lowest segment, filling
+/// any gaps between them with zeroes (like `objcopy -O binary`)
+pub fn flatten(elf: &[u8]) -> Result<FlatElf, BBBSError> {
+    let file = object::File::parse(elf).map_err(|e| BBBSError::InvalidElf(e.to_string()))?;
+
+    let mut segments = vec![];
+    for segment in file.segments() {
+        let data = segment
+            .data()
+            .map_err(|e| BBBSError::InvalidElf(e.to_string()))?;
+        if !data.is_empty() {
+            segments.push((segment.address(), data));
+        }
+    }
+
+    let base = segments.iter().map(|(addr, _)| *addr).min().unwrap_or(0);
+    let mut end = base;
+    for (addr, data) in &segments {
+        let segment_end = addr.checked_add(data.len() as u64).ok_or_else(|| {
+            BBBSError::InvalidElf(format!(
+                "segment at 0x{addr:X} runs past the end of the address space"
+            ))
+        })?;
+        end = end.max(segment_end);
+    }
+
+    if end - base > MAX_FLAT_SIZE {
+        return Err(BBBSError::InvalidElf(format!(
+            "loadable segments span 0x{:X} bytes",
+            end - base
+        )));
+    }
+
+    // every segment ends by `end`, so each one fits in the image
+    let mut rv = vec![0; (end - base) as usize];
+    for (addr, data) in segments {
+        let start = (addr - base) as usize;
+        rv[start..start + data.len()].copy_from_slice(data);
+    }
+
+    Ok(FlatElf {
+        data: rv,
+        base,
+        entrypoint: file.entry() as u32,
+    })
+}
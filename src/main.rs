@@ -1,7 +1,18 @@
-use anyhow::Result;
+use std::process::ExitCode;
 
-fn main() -> Result<()> {
+use bbbs::BBBSError;
+
+fn main() -> ExitCode {
     let args = bbbs::args::parse_args();
 
-    bbbs::build(args)
+    match bbbs::run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            ExitCode::from(
+                e.downcast_ref::<BBBSError>()
+                    .map_or(1, BBBSError::exit_code),
+            )
+        }
+    }
 }
@@ -0,0 +1,2428 @@
+use anyhow::{bail, Result};
+use bb::{
+    bootrom_keys, BbAesIv, BbAesKey, BbShaHash, BootromError, HashHex, Virage2, BOOTROM_SIZE,
+    SK_IV_START,
+};
+use clap::ValueEnum;
+use flate2::{write::GzEncoder, Compression};
+#[cfg(feature = "sign")]
+use rsa::{
+    pkcs1::DecodeRsaPrivateKey, pkcs8::DecodePrivateKey, traits::PublicKeyParts, Pkcs1v15Sign,
+    RsaPrivateKey,
+};
+use serde_json::json;
+use subtle::ConstantTimeEq;
+use thiserror::Error;
+use zeroize::Zeroizing;
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub use crate::builder::SksaBuilder;
+pub use crate::bundle::Bundle;
+pub use bb::CmdHead;
+
+use crate::args::{
+    Args, BatchArgs, BuildArgs, BundleArgs, Command, ExtractArgs, IOType, InfoArgs, KeyArgs,
+    KeysArgs, LayoutArgs, ListArgs, MappedFile, MkVirage2Args, PatchArgs, RecryptArgs, SksaInput,
+    ValidateArgs, VerifyArgs,
+};
+use crate::crypto::{
+    cbc_decrypt_in_place, cbc_encrypt_in_place, check_sk_with, ctr_advance, ctr_apply_in_place,
+    derive_sa1_key, CryptoError, DefaultSha1, Sha1Backend, AES_BLOCK_SIZE,
+};
+
+/// Size of the SK at the start of an SKSA (unless overridden with `sk_size`)
+pub const SK_SIZE: usize = 64 * 1024;
+const SA1_CMD_HEAD_SIZE: usize = CmdHead::SIZE;
+/// Size of the info block (CMD head and padding) before each SA
+pub const SA1_INFO_BLOCK_SIZE: usize = 16 * 1024;
+/// Smallest SKSA there can be: an SK and the SA1's info block
+pub const SKSA_MIN_BYTES: usize = SK_SIZE + SA1_INFO_BLOCK_SIZE;
+
+const BOOTROM_MIN_BYTES: usize = SK_IV_START + size_of::<BbAesIv>();
+
+/// Default size of the ROM header at the start of the SA1
+pub const ROM_HEADER_SIZE: usize = 4 * 1024;
+/// Offset of the entrypoint within the ROM header
+pub const ENTRYPOINT_OFFSET: usize = 2 * size_of::<u32>();
+
+/// Address the SA1 is decompressed to, used as the default entrypoint
+pub const UNZIP_BUF_OFFSET: u32 = 0x80300000;
+
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+static STATS: AtomicBool = AtomicBool::new(false);
+static STAGES: Mutex<Vec<(&'static str, Duration, usize)>> = Mutex::new(vec![]);
+
+pub fn set_stats(stats: bool) {
+    STATS.store(stats, Ordering::Relaxed);
+}
+
+/// Adds the time since `start` and `bytes` to `stage`'s totals, if stats are enabled
+fn record_stage(stage: &'static str, start: Instant, bytes: usize) {
+    if !STATS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let elapsed = start.elapsed();
+    let mut stages = STAGES.lock().unwrap();
+    match stages.iter_mut().find(|(name, ..)| *name == stage) {
+        Some((_, time, total)) => {
+            *time += elapsed;
+            *total += bytes;
+        }
+        None => stages.push((stage, elapsed, bytes)),
+    }
+}
+
+fn print_stats() {
+    eprintln!("{:<16} {:>12} {:>12}", "stage", "time", "bytes");
+    for (stage, time, bytes) in STAGES.lock().unwrap().drain(..) {
+        eprintln!(
+            "{stage:<16} {:>12} {:>12}",
+            format!("{time:.2?}"),
+            format!("0x{bytes:X}")
+        );
+    }
+}
+
+fn write_output<T: AsRef<[u8]>>(outfile: &IOType, data: T) -> std::io::Result<()> {
+    outfile.write(&data)?;
+    report_written(data.as_ref().len(), outfile);
+    Ok(())
+}
+
+fn report_written(len: usize, outfile: &IOType) {
+    if !QUIET.load(Ordering::Relaxed) {
+        eprintln!("Wrote 0x{len:X} bytes to {outfile}");
+    }
+}
+
+macro_rules! verbose {
+    ($($arg:tt)*) => {
+        if VERBOSE.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!("WARNING: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+#[derive(Debug, Error)]
+pub enum BBBSError {
+    #[error("Provided SKSA is too short (got 0x{0:X} bytes, expected 0x{1:X})")]
+    SKSATooShort(usize, usize),
+
+    #[error("Provided bootrom is too short (got 0x{0:X} bytes, expected 0x{BOOTROM_MIN_BYTES:X})")]
+    BootromTooShort(usize),
+
+    #[error(
+        "Provided payload is too long to fit in the provided SA1 (got 0x{0:X} bytes, max 0x{1:X})"
+    )]
+    PayloadTooLong(usize, u32),
+
+    #[error(
+        "Provided payload is too long to fit in the provided SA1 after compression (got 0x{0:X} bytes (0x{1:X} uncompressed), max 0x{2:X})"
+    )]
+    CompressedPayloadTooLong(usize, usize, u32),
+
+    #[error("Invalid SK hash (got {0}, expected {1}")]
+    InvalidSKHash(String, String),
+
+    #[error(
+        "Provided SKSA is too short to contain the selected SA (got 0x{0:X} bytes, expected 0x{1:X})"
+    )]
+    SATooShort(usize, usize),
+
+    #[error("Failed to parse SA1 CmdHead: {0}")]
+    CmdHeadParse(binrw::Error),
+
+    #[error("Failed to parse Virage2: {0}")]
+    Virage2Parse(binrw::Error),
+
+    #[error(transparent)]
+    Bootrom(#[from] BootromError),
+
+    #[error("Failed to decrypt {0}: {1}")]
+    Decryption(&'static str, String),
+
+    #[error("Failed to encrypt {0}: {1}")]
+    Encryption(&'static str, String),
+
+    #[error("Offset 0x{0:X} (+0x{1:X} bytes) is past the end of the provided {2} (0x{3:X} bytes)")]
+    RegionOutOfBounds(usize, usize, &'static str, usize),
+
+    #[error("Decrypted SA1 does not match the plaintext SA1")]
+    RoundtripMismatch,
+
+    #[error("Provided Virage2 does not look valid ({0})")]
+    InvalidVirage2(&'static str),
+
+    #[error("SA1 size in CmdHead (0x{0:X} bytes) is smaller than the ROM header (0x{1:X} bytes)")]
+    CmdSizeTooSmall(u32, usize),
+
+    #[error(
+        "Provided payload already looks like a built {0} (pass --allow-prebuilt to allow this)"
+    )]
+    PrebuiltPayload(&'static str),
+
+    #[error("Invalid SA1 hash in the input SKSA (got {0}, expected {1})")]
+    InvalidSA1Hash(String, String),
+
+    #[error("Decrypted SK does not match the expected SK (first difference at offset 0x{0:X})")]
+    SKMismatch(usize),
+
+    #[error("Provided payload is empty (pass --allow-empty to allow this)")]
+    EmptyPayload,
+
+    #[error("Failed to map output file: {0}")]
+    MapOutput(std::io::Error),
+
+    #[error("No {0} was provided")]
+    MissingInput(&'static str),
+
+    #[error("Refusing to overwrite input file {} (pass --force to allow this)", .0.display())]
+    WouldOverwriteInput(PathBuf),
+
+    #[cfg(feature = "sign")]
+    #[error("Failed to parse signing key: {0}")]
+    InvalidSignKey(String),
+
+    #[cfg(feature = "sign")]
+    #[error("Failed to sign SA1: {0}")]
+    Signing(String),
+
+    #[error("SA1 ROM header (0x{0:X} bytes) is too small to hold the entrypoint at 0x{ENTRYPOINT_OFFSET:X}")]
+    EntrypointOutOfBounds(usize),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("SK size 0x{0:X} is not a non-zero multiple of the AES block size")]
+    InvalidSKSize(usize),
+
+    #[error("Provided SK is 0x{0:X} bytes, expected 0x{1:X} (pass --sk-size to change this)")]
+    SKPartSize(usize, usize),
+
+    #[error("Provided SKSA ends after the SA1 info block (0x{0:X} bytes) and has no SA1 to read")]
+    MissingSA1Region(usize),
+
+    #[error("Provided bundle is not valid ({0})")]
+    InvalidBundle(&'static str),
+
+    #[error("Invalid payload hash (got {0}, expected {1})")]
+    PayloadHashMismatch(String, String),
+
+    #[error("SA1 size (0x{0:X} bytes) is not a multiple of the AES block size (0x{AES_BLOCK_SIZE:X} bytes)")]
+    SA1NotBlockAligned(usize),
+
+    #[error("SA1 doesn't match the payload (first difference at SA1 offset 0x{0:X})")]
+    PayloadMismatch(usize),
+
+    #[error("SA{0} would be encrypted with the same key and IV as SA{1}")]
+    IvReuse(usize, usize),
+
+    #[error("Invalid ELF payload: {0}")]
+    InvalidElf(String),
+
+    #[error("Reading back {0} didn't match what was written (first difference at 0x{1:X})")]
+    WriteVerifyFailed(PathBuf, usize),
+
+    #[error("Extra payload at 0x{0:X} overlaps the data before it, which ends at 0x{1:X}")]
+    PayloadOverlap(usize, usize),
+
+    #[error("Extra payload ends at 0x{0:X}, past the end of the SA1 (0x{1:X} bytes)")]
+    ExtraPayloadTooLong(usize, u32),
+
+    #[error("Patch of 0x{1:X} bytes at 0x{0:X} doesn't fit in the SA1 (0x{2:X} bytes)")]
+    PatchOutOfBounds(usize, usize, usize),
+
+    #[error("More than one batch payload would be written to {0}")]
+    DuplicateOutput(PathBuf),
+
+    #[cfg(feature = "sign")]
+    #[error(
+        "Can't sign the SA1 and preserve the rest of the SKSA, as the signature would move it"
+    )]
+    SignedPreservedTail,
+}
+
+/// Defines [`BBBSError::name`], [`BBBSError::exit_code`] and [`EXIT_CODES`] from one list, so
+/// every error has a code and the help text can't fall out of step with it
+macro_rules! exit_codes {
+    ($($(#[$attr:meta])* $variant:ident $(($($fields:tt)*))? = $code:literal,)*) => {
+        impl BBBSError {
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $($(#[$attr])* Self::$variant $(($($fields)*))? => stringify!($variant),)*
+                }
+            }
+
+            /// Process exit code for this error; 1 is used for any other error, and 2 for invalid
+            /// arguments
+            pub fn exit_code(&self) -> u8 {
+                match self {
+                    $($(#[$attr])* Self::$variant $(($($fields)*))? => $code,)*
+                }
+            }
+        }
+
+        /// Each [`BBBSError`]'s name and [`exit code`](BBBSError::exit_code), in code order
+        pub const EXIT_CODES: &[(&str, u8)] = &[$($(#[$attr])* (stringify!($variant), $code),)*];
+    };
+}
+
+exit_codes! {
+    SKSATooShort(..) = 3,
+    BootromTooShort(..) = 4,
+    PayloadTooLong(..) = 5,
+    CompressedPayloadTooLong(..) = 6,
+    InvalidSKHash(..) = 7,
+    SATooShort(..) = 8,
+    CmdHeadParse(..) = 9,
+    Virage2Parse(..) = 10,
+    Bootrom(..) = 11,
+    Decryption(..) = 12,
+    Encryption(..) = 13,
+    RegionOutOfBounds(..) = 14,
+    RoundtripMismatch = 15,
+    InvalidVirage2(..) = 16,
+    CmdSizeTooSmall(..) = 17,
+    PrebuiltPayload(..) = 18,
+    // 19 was InvalidSA1Key, which was never returned; it stays reserved so that scripts checking
+    // for it don't see it mean something else
+    InvalidSA1Hash(..) = 20,
+    SKMismatch(..) = 21,
+    EmptyPayload = 22,
+    MapOutput(..) = 23,
+    MissingInput(..) = 24,
+    WouldOverwriteInput(..) = 25,
+    #[cfg(feature = "sign")]
+    InvalidSignKey(..) = 26,
+    #[cfg(feature = "sign")]
+    Signing(..) = 27,
+    EntrypointOutOfBounds(..) = 28,
+    Io(..) = 29,
+    InvalidSKSize(..) = 30,
+    SKPartSize(..) = 31,
+    MissingSA1Region(..) = 32,
+    InvalidBundle(..) = 33,
+    PayloadHashMismatch(..) = 34,
+    SA1NotBlockAligned(..) = 35,
+    PayloadMismatch(..) = 36,
+    IvReuse(..) = 37,
+    InvalidElf(..) = 38,
+    WriteVerifyFailed(..) = 39,
+    PayloadOverlap(..) = 40,
+    ExtraPayloadTooLong(..) = 41,
+    PatchOutOfBounds(..) = 42,
+    #[cfg(feature = "sign")]
+    SignedPreservedTail = 43,
+    DuplicateOutput(..) = 44,
+}
+
+impl BBBSError {
+    fn from_hashes(calculated: BbShaHash, expected: BbShaHash) -> Self {
+        Self::InvalidSKHash(calculated.to_hex(), expected.to_hex())
+    }
+}
+
+#[derive(Debug)]
+pub struct BuildReport<O = Vec<u8>> {
+    pub output: O,
+    /// The SK hash from the virage2, if there was one; builds from just the SA1 key don't have it
+    pub sk_hash: Option<BbShaHash>,
+    pub sa1_hash: BbShaHash,
+    pub payload_len: usize,
+    /// Length of the payload as stored in the SA1, so after compression if it was compressed
+    pub stored_len: usize,
+    pub padded_len: usize,
+    pub padding: usize,
+    pub entrypoint: u32,
+    /// The padded plaintext SA1, as it was before encryption
+    pub plain_sa1: Vec<u8>,
+}
+
+/// Where an assembled SKSA is written: an in-memory buffer or a memory-mapped file
+#[derive(Debug)]
+pub enum Output {
+    Memory(Vec<u8>),
+    Mapped(MappedFile),
+}
+
+impl AsRef<[u8]> for Output {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Self::Memory(v) => v,
+            Self::Mapped(m) => m.as_ref(),
+        }
+    }
+}
+
+impl AsMut<[u8]> for Output {
+    fn as_mut(&mut self) -> &mut [u8] {
+        match self {
+            Self::Memory(v) => v,
+            Self::Mapped(m) => m.as_mut(),
+        }
+    }
+}
+
+fn memory_output(len: usize) -> Result<Vec<u8>, BBBSError> {
+    Ok(vec![0; len])
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Endian {
+    #[default]
+    Big,
+    Little,
+}
+
+impl Endian {
+    fn u32_bytes(self, value: u32) -> [u8; 4] {
+        match self {
+            Self::Big => value.to_be_bytes(),
+            Self::Little => value.to_le_bytes(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum IvSource {
+    #[default]
+    Header,
+    Zero,
+}
+
+impl IvSource {
+    fn iv(self, cmd: &CmdHead) -> BbAesIv {
+        match self {
+            Self::Header => cmd.iv,
+            Self::Zero => BbAesIv::default(),
+        }
+    }
+}
+
+/// How the payload file is laid out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    /// A flat binary, used as-is
+    #[default]
+    Raw,
+    /// An ELF, whose loadable segments are flattened into a binary
+    Elf,
+}
+
+/// How the SA1 is encrypted; CTR uses the IV as the initial counter
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CipherMode {
+    #[default]
+    Cbc,
+    Ctr,
+}
+
+impl CipherMode {
+    fn encrypt(
+        self,
+        data: &[u8],
+        key: &BbAesKey,
+        iv: &BbAesIv,
+        name: &'static str,
+    ) -> Result<Vec<u8>, BBBSError> {
+        match self {
+            Self::Cbc => encrypt_cbc(data, key, iv, name),
+            Self::Ctr => Ok(apply_ctr(data, key, iv)),
+        }
+    }
+
+    fn decrypt(
+        self,
+        data: &[u8],
+        key: &BbAesKey,
+        iv: &BbAesIv,
+        name: &'static str,
+    ) -> Result<Vec<u8>, BBBSError> {
+        match self {
+            Self::Cbc => decrypt_cbc(data, key, iv, name),
+            Self::Ctr => Ok(apply_ctr(data, key, iv)),
+        }
+    }
+}
+
+/// The stages of a build, in order; see [`BuildOptions::on_stage`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStage {
+    ReadSksa,
+    DecryptSk,
+    VerifyHash,
+    DeriveKey,
+    EncryptSa1,
+    Write,
+}
+
+/// A callback for [`BuildOptions::on_stage`]
+#[derive(Clone)]
+pub struct StageHook(pub Arc<dyn Fn(BuildStage) + Send + Sync>);
+
+impl std::fmt::Debug for StageHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StageHook")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildOptions {
+    pub entrypoint: u32,
+    pub endian: Endian,
+    pub compress: bool,
+    pub check_roundtrip: bool,
+    pub sa1_only: bool,
+    pub pad_byte: u8,
+    pub sa_index: usize,
+    pub sk_size: usize,
+    pub header_size: usize,
+    pub payload_offset: usize,
+    pub stub: Vec<u8>,
+    /// More data to place after the payload, each at an offset from the start of the SA1; gaps
+    /// are filled with `pad_byte`
+    pub extra_payloads: Vec<(usize, Vec<u8>)>,
+    pub allow_prebuilt: bool,
+    pub allow_empty: bool,
+    pub preserve_tail: bool,
+    pub sa1_iv_from: IvSource,
+    pub cipher_mode: CipherMode,
+    /// Common IV to derive the SA1 key with instead of the one in the CmdHead
+    pub common_cmd_iv: Option<BbAesIv>,
+    pub progress: bool,
+    pub sha1_backend: Option<Arc<dyn Sha1Backend + Send + Sync>>,
+    /// Called as the build enters each stage; stages that don't apply (such as decrypting the SK
+    /// when only its hash is known) are skipped
+    pub on_stage: Option<StageHook>,
+    /// Key to sign the encrypted SA1 with; see [`sign_sa1`]
+    #[cfg(feature = "sign")]
+    pub sign_key: Option<Arc<RsaPrivateKey>>,
+}
+
+impl Default for BuildOptions {
+    fn default() -> Self {
+        Self {
+            entrypoint: UNZIP_BUF_OFFSET,
+            endian: Endian::Big,
+            compress: false,
+            check_roundtrip: false,
+            sa1_only: false,
+            pad_byte: 0,
+            sa_index: 1,
+            sk_size: SK_SIZE,
+            header_size: ROM_HEADER_SIZE,
+            payload_offset: 0,
+            stub: vec![],
+            extra_payloads: vec![],
+            allow_prebuilt: false,
+            allow_empty: false,
+            preserve_tail: false,
+            sa1_iv_from: IvSource::Header,
+            cipher_mode: CipherMode::Cbc,
+            common_cmd_iv: None,
+            progress: false,
+            sha1_backend: None,
+            on_stage: None,
+            #[cfg(feature = "sign")]
+            sign_key: None,
+        }
+    }
+}
+
+impl BuildOptions {
+    fn stage(&self, stage: BuildStage) {
+        if let Some(StageHook(hook)) = &self.on_stage {
+            hook(stage);
+        }
+    }
+
+    pub fn payload_start(&self) -> usize {
+        self.header_size + self.stub.len() + self.payload_offset
+    }
+
+    fn sha1(&self) -> &dyn Sha1Backend {
+        match &self.sha1_backend {
+            Some(backend) => backend.as_ref(),
+            None => &DefaultSha1,
+        }
+    }
+}
+
+/// Parses an RSA private key from a PKCS#8 or PKCS#1 PEM file
+#[cfg(feature = "sign")]
+pub fn parse_sign_key(pem: &str) -> Result<RsaPrivateKey, BBBSError> {
+    RsaPrivateKey::from_pkcs8_pem(pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs1_pem(pem))
+        .map_err(|e| BBBSError::InvalidSignKey(e.to_string()))
+}
+
+/// Signs the encrypted SA1 bytes (not including the SK or info block) with RSASSA-PKCS1-v1_5
+/// over their SHA-1 hash. The signature is as long as the key's modulus and is written directly
+/// after the SA1
+#[cfg(feature = "sign")]
+pub fn sign_sa1(
+    key: &RsaPrivateKey,
+    sa1_enc: &[u8],
+    backend: &dyn Sha1Backend,
+) -> Result<Vec<u8>, BBBSError> {
+    key.sign(Pkcs1v15Sign::new::<sha1::Sha1>(), &backend.digest(sa1_enc))
+        .map_err(|e| BBBSError::Signing(e.to_string()))
+}
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::best());
+    encoder
+        .write_all(data)
+        .expect("writing to a Vec should never fail");
+    encoder
+        .finish()
+        .expect("writing to a Vec should never fail")
+}
+
+pub fn make_sa1(payload: Vec<u8>, options: &BuildOptions) -> Result<Vec<u8>, BBBSError> {
+    Ok(make_sa1_with_len(payload, options)?.0)
+}
+
+/// Like [`make_sa1`], but also returns the length of the payload as stored
+fn make_sa1_with_len(
+    payload: Vec<u8>,
+    options: &BuildOptions,
+) -> Result<(Vec<u8>, usize), BBBSError> {
+    if ENTRYPOINT_OFFSET + 4 > options.header_size {
+        return Err(BBBSError::EntrypointOutOfBounds(options.header_size));
+    }
+
+    let mut rv = vec![0; options.header_size];
+
+    let entrypoint = options.endian.u32_bytes(options.entrypoint);
+    rv[ENTRYPOINT_OFFSET..ENTRYPOINT_OFFSET + 4].copy_from_slice(&entrypoint);
+
+    rv.extend(&options.stub);
+    rv.resize(options.payload_start(), 0);
+
+    if options.compress {
+        rv.extend(gzip(&payload));
+    } else {
+        rv.extend(payload);
+    }
+    let stored_len = rv.len() - options.payload_start();
+
+    let mut extra: Vec<_> = options.extra_payloads.iter().collect();
+    extra.sort_by_key(|(offset, _)| *offset);
+    for (offset, data) in extra {
+        if *offset < rv.len() {
+            return Err(BBBSError::PayloadOverlap(*offset, rv.len()));
+        }
+        rv.resize(*offset, options.pad_byte);
+        rv.extend(data);
+    }
+
+    Ok((rv, stored_len))
+}
+
+fn sha1(data: &[u8]) -> BbShaHash {
+    DefaultSha1.digest(data)
+}
+
+fn read_virage2(buf: &[u8]) -> Result<Virage2, BBBSError> {
+    let virage2 = Virage2::read_from_buf(buf).map_err(BBBSError::Virage2Parse)?;
+
+    if virage2.sk_hash.iter().all(|&b| b == 0) {
+        return Err(BBBSError::InvalidVirage2("SK hash is all zeroes"));
+    }
+
+    if virage2.boot_app_key.iter().all(|&b| b == 0) {
+        return Err(BBBSError::InvalidVirage2("boot app key is all zeroes"));
+    }
+
+    Ok(virage2)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SkCheck<'a> {
+    Bootrom(&'a [u8]),
+    Hash(BbShaHash),
+    Skip,
+}
+
+fn encrypt_cbc(
+    data: &[u8],
+    key: &BbAesKey,
+    iv: &BbAesIv,
+    name: &'static str,
+) -> Result<Vec<u8>, BBBSError> {
+    let mut rv = data.to_vec();
+    cbc_encrypt_in_place(&mut rv, key, iv)
+        .map_err(|e| BBBSError::Encryption(name, e.to_string()))?;
+    Ok(rv)
+}
+
+fn apply_ctr(data: &[u8], key: &BbAesKey, counter: &BbAesIv) -> Vec<u8> {
+    let mut rv = data.to_vec();
+    ctr_apply_in_place(&mut rv, key, counter);
+    rv
+}
+
+const PROGRESS_CHUNK_SIZE: usize = 64 * 1024;
+
+fn encrypt_with_progress(
+    data: &[u8],
+    key: &BbAesKey,
+    iv: &BbAesIv,
+    mode: CipherMode,
+    name: &'static str,
+) -> Result<Vec<u8>, BBBSError> {
+    if !data.len().is_multiple_of(AES_BLOCK_SIZE) {
+        return Err(BBBSError::Encryption(
+            name,
+            CryptoError::NotBlockAligned(data.len()).to_string(),
+        ));
+    }
+
+    let mut rv = data.to_vec();
+    let mut iv = *iv;
+    let mut done = 0;
+
+    for chunk in rv.chunks_mut(PROGRESS_CHUNK_SIZE) {
+        match mode {
+            CipherMode::Cbc => {
+                cbc_encrypt_in_place(chunk, key, &iv)
+                    .map_err(|e| BBBSError::Encryption(name, e.to_string()))?;
+                iv.copy_from_slice(&chunk[chunk.len() - AES_BLOCK_SIZE..]);
+            }
+            CipherMode::Ctr => {
+                ctr_apply_in_place(chunk, key, &iv);
+                iv = ctr_advance(&iv, chunk.len() / AES_BLOCK_SIZE);
+            }
+        }
+
+        done += chunk.len();
+        eprint!("\rEncrypting {name}: {:3}%", done * 100 / data.len());
+    }
+    eprintln!();
+
+    Ok(rv)
+}
+
+fn decrypt_cbc(
+    data: &[u8],
+    key: &BbAesKey,
+    iv: &BbAesIv,
+    name: &'static str,
+) -> Result<Vec<u8>, BBBSError> {
+    let mut rv = data.to_vec();
+    cbc_decrypt_in_place(&mut rv, key, iv)
+        .map_err(|e| BBBSError::Decryption(name, e.to_string()))?;
+    Ok(rv)
+}
+
+/// The SK key and IV from `bootrom`, checking it's long enough to have them
+fn sk_keys(bootrom: &[u8]) -> Result<(Zeroizing<BbAesKey>, Zeroizing<BbAesIv>), BBBSError> {
+    if bootrom.len() < BOOTROM_MIN_BYTES {
+        return Err(BBBSError::BootromTooShort(bootrom.len()));
+    }
+
+    let (sk_key, sk_iv) = bootrom_keys(bootrom)?;
+    Ok((Zeroizing::new(sk_key), Zeroizing::new(sk_iv)))
+}
+
+fn decrypt_sk(sk: &[u8], bootrom: &[u8]) -> Result<Zeroizing<Vec<u8>>, BBBSError> {
+    let (sk_key, sk_iv) = sk_keys(bootrom)?;
+
+    let sk = Zeroizing::new(decrypt_cbc(sk, &sk_key, &sk_iv, "SK")?);
+
+    verbose!("decrypted SK");
+
+    Ok(sk)
+}
+
+fn hash_diff(got: &BbShaHash, expected: &BbShaHash) -> String {
+    let first = got
+        .iter()
+        .zip(expected)
+        .position(|(a, b)| a != b)
+        .unwrap_or(0);
+    format!(
+        "SK hash mismatch:\n  got:      {}\n  expected: {}\n            {}^^",
+        got.to_hex(),
+        expected.to_hex(),
+        " ".repeat(first * 2)
+    )
+}
+
+fn check_sk_hash(
+    sk: &[u8],
+    virage2: &Virage2,
+    sk_check: SkCheck,
+    backend: &dyn Sha1Backend,
+) -> Result<(), BBBSError> {
+    let mismatch = |calculated: BbShaHash, expected: BbShaHash| {
+        verbose!("{}", hash_diff(&calculated, &expected));
+        BBBSError::from_hashes(calculated, expected)
+    };
+
+    match sk_check {
+        SkCheck::Bootrom(bootrom) => {
+            let (sk_key, sk_iv) = sk_keys(bootrom)?;
+            let mut sk = Zeroizing::new(sk.to_vec());
+
+            let start = Instant::now();
+            let checked = check_sk_with(&mut sk, &sk_key, &sk_iv, &virage2.sk_hash, backend);
+            record_stage("SK decrypt+hash", start, sk.len());
+
+            match checked {
+                Err(CryptoError::SKHashMismatch {
+                    calculated,
+                    expected,
+                }) => return Err(mismatch(calculated, expected)),
+                Err(e) => return Err(BBBSError::Decryption("SK", e.to_string())),
+                Ok(()) => verbose!("decrypted SK"),
+            }
+        }
+        SkCheck::Hash(sk_hash) => {
+            if !bool::from(sk_hash.ct_eq(&virage2.sk_hash)) {
+                return Err(mismatch(sk_hash, virage2.sk_hash));
+            }
+        }
+        SkCheck::Skip => {
+            verbose!("skipped SK verification");
+            return Ok(());
+        }
+    }
+
+    verbose!("SK hash matched");
+
+    Ok(())
+}
+
+/// Returns the `sk_size`-byte SK at the start of `sksa`, checking there is room for an info block
+/// after it
+fn split_sk(sksa: &[u8], sk_size: usize) -> Result<&[u8], BBBSError> {
+    if sk_size == 0 || !sk_size.is_multiple_of(AES_BLOCK_SIZE) {
+        return Err(BBBSError::InvalidSKSize(sk_size));
+    }
+
+    if sksa.len() < sk_size + SA1_INFO_BLOCK_SIZE {
+        return Err(BBBSError::SKSATooShort(
+            sksa.len(),
+            sk_size + SA1_INFO_BLOCK_SIZE,
+        ));
+    }
+
+    Ok(&sksa[..sk_size])
+}
+
+pub fn verify_sk(sksa: &[u8], virage2: &[u8], sk_check: SkCheck) -> Result<(), BBBSError> {
+    verify_sk_sized(sksa, SK_SIZE, virage2, sk_check)
+}
+
+/// Like [`verify_sk`], for an SK of `sk_size` bytes
+pub fn verify_sk_sized(
+    sksa: &[u8],
+    sk_size: usize,
+    virage2: &[u8],
+    sk_check: SkCheck,
+) -> Result<(), BBBSError> {
+    let sk = split_sk(sksa, sk_size)?;
+
+    let virage2 = read_virage2(virage2)?;
+
+    check_sk_hash(sk, &virage2, sk_check, &DefaultSha1)
+}
+
+/// Returns the `(offset, length)` of each run of bytes that differ between `a` and `b`, counting
+/// any bytes past the end of the shorter one as differing
+pub fn diff_ranges(a: &[u8], b: &[u8]) -> Vec<(usize, usize)> {
+    let mut rv: Vec<(usize, usize)> = vec![];
+
+    for (i, _) in a.iter().zip(b).enumerate().filter(|(_, (a, b))| a != b) {
+        match rv.last_mut() {
+            Some((offset, len)) if *offset + *len == i => *len += 1,
+            _ => rv.push((i, 1)),
+        }
+    }
+
+    let common = a.len().min(b.len());
+    let extra = a.len().max(b.len()) - common;
+    if extra > 0 {
+        match rv.last_mut() {
+            Some((offset, len)) if *offset + *len == common => *len += extra,
+            _ => rv.push((common, extra)),
+        }
+    }
+
+    rv
+}
+
+/// Returns the index of the first AES block that differs between two plaintexts; with the same key
+/// and IV, every ciphertext block from that one onwards will differ too
+pub fn first_changed_block(old: &[u8], new: &[u8]) -> Option<usize> {
+    match old.iter().zip(new).position(|(a, b)| a != b) {
+        Some(offset) => Some(offset / AES_BLOCK_SIZE),
+        None if old.len() != new.len() => Some(old.len().min(new.len()) / AES_BLOCK_SIZE),
+        None => None,
+    }
+}
+
+pub fn compare_sk(sksa: &[u8], bootrom: &[u8], expected: &[u8]) -> Result<(), BBBSError> {
+    compare_sk_sized(sksa, SK_SIZE, bootrom, expected)
+}
+
+fn compare_sk_sized(
+    sksa: &[u8],
+    sk_size: usize,
+    bootrom: &[u8],
+    expected: &[u8],
+) -> Result<(), BBBSError> {
+    let sk = decrypt_sk(split_sk(sksa, sk_size)?, bootrom)?;
+
+    match sk.iter().zip(expected).position(|(a, b)| a != b) {
+        Some(offset) => Err(BBBSError::SKMismatch(offset)),
+        None if sk.len() != expected.len() => {
+            Err(BBBSError::SKMismatch(sk.len().min(expected.len())))
+        }
+        None => Ok(()),
+    }
+}
+
+pub fn read_cmd_head_at(sksa: &[u8], offset: usize) -> Result<CmdHead, BBBSError> {
+    if sksa.len() < offset + SA1_INFO_BLOCK_SIZE {
+        return Err(BBBSError::SATooShort(
+            sksa.len(),
+            offset + SA1_INFO_BLOCK_SIZE,
+        ));
+    }
+
+    let cmd = &sksa[offset..offset + SA1_CMD_HEAD_SIZE];
+    CmdHead::read_from_buf(cmd).map_err(BBBSError::CmdHeadParse)
+}
+
+pub fn read_cmd_head(sksa: &[u8]) -> Result<CmdHead, BBBSError> {
+    read_cmd_head_sized(sksa, SK_SIZE)
+}
+
+/// Like [`read_cmd_head`], for an SK of `sk_size` bytes
+pub fn read_cmd_head_sized(sksa: &[u8], sk_size: usize) -> Result<CmdHead, BBBSError> {
+    split_sk(sksa, sk_size)?;
+    read_cmd_head_at(sksa, sk_size)
+}
+
+pub fn sa_offset(sksa: &[u8], sa_index: usize) -> Result<usize, BBBSError> {
+    sa_offset_sized(sksa, SK_SIZE, sa_index)
+}
+
+fn sa_offset_sized(sksa: &[u8], sk_size: usize, sa_index: usize) -> Result<usize, BBBSError> {
+    let mut offset = split_sk(sksa, sk_size)?.len();
+
+    for _ in 1..sa_index {
+        let cmd = read_cmd_head_at(sksa, offset)?;
+        offset += SA1_INFO_BLOCK_SIZE + cmd.size as usize;
+    }
+
+    Ok(offset)
+}
+
+/// Assembles an SKSA from a separate SK and SA command block (the info block, optionally followed
+/// by the SA itself)
+pub fn assemble_sksa(sk: &[u8], cmd: &[u8], sk_size: usize) -> Result<Vec<u8>, BBBSError> {
+    if sk.len() != sk_size {
+        return Err(BBBSError::SKPartSize(sk.len(), sk_size));
+    }
+
+    let rv = [sk, cmd].concat();
+    split_sk(&rv, sk_size)?;
+
+    Ok(rv)
+}
+
+/// Returns the size of the SKSA that building into `sksa` with `options` will produce, without
+/// needing keys or a payload; this is everything [`build_sksa_into`] passes to its `alloc`
+pub fn output_size(sksa: &[u8], options: &BuildOptions) -> Result<usize, BBBSError> {
+    let sa_offset = sa_offset_sized(sksa, options.sk_size, options.sa_index)?;
+    let sa_size = read_cmd_head_at(sksa, sa_offset)?.size as usize;
+
+    #[cfg(feature = "sign")]
+    let signature = match &options.sign_key {
+        Some(_) if options.preserve_tail && !options.sa1_only => {
+            return Err(BBBSError::SignedPreservedTail)
+        }
+        Some(key) => key.size(),
+        None => 0,
+    };
+    #[cfg(not(feature = "sign"))]
+    let signature = 0;
+
+    if options.sa1_only {
+        return Ok(sa_size + signature);
+    }
+
+    let prefix = sa_offset + SA1_INFO_BLOCK_SIZE;
+    let tail = match options.preserve_tail {
+        true => sksa.len().saturating_sub(prefix + sa_size),
+        false => 0,
+    };
+    Ok(prefix + sa_size + signature + tail)
+}
+
+/// Walks the SA command blocks after the SK, returning the offset and `CmdHead` of each; stops at
+/// the first block whose info block is truncated or whose size is zero
+pub fn list_sas(sksa: &[u8]) -> Result<Vec<(usize, CmdHead)>, BBBSError> {
+    list_sas_sized(sksa, SK_SIZE)
+}
+
+/// Like [`list_sas`], for an SK of `sk_size` bytes
+pub fn list_sas_sized(sksa: &[u8], sk_size: usize) -> Result<Vec<(usize, CmdHead)>, BBBSError> {
+    let mut rv = vec![];
+    let mut offset = sk_size;
+
+    while sksa.len() >= offset + SA1_INFO_BLOCK_SIZE {
+        let cmd = read_cmd_head_at(sksa, offset)?;
+        if cmd.size == 0 {
+            break;
+        }
+
+        let next = offset + SA1_INFO_BLOCK_SIZE + cmd.size as usize;
+        rv.push((offset, cmd));
+        offset = next;
+    }
+
+    Ok(rv)
+}
+
+/// Returns the (1-based) indices of the other SAs in `sksa` encrypted under the same key and IV
+/// that rebuilding SA `sa_index` would use; the key is the same whenever the CmdHead's key and
+/// common IV are
+pub fn reused_iv_sas(
+    sksa: &[u8],
+    sk_size: usize,
+    sa_index: usize,
+    iv_from: IvSource,
+) -> Result<Vec<usize>, BBBSError> {
+    let sas = list_sas_sized(sksa, sk_size)?;
+    let Some((_, cmd)) = sas.get(sa_index.wrapping_sub(1)) else {
+        return Ok(vec![]);
+    };
+    let iv = iv_from.iv(cmd);
+
+    Ok(sas
+        .iter()
+        .enumerate()
+        .filter(|&(i, (_, other))| {
+            i + 1 != sa_index
+                && other.key == cmd.key
+                && other.common_cmd_iv == cmd.common_cmd_iv
+                && other.iv == iv
+        })
+        .map(|(i, _)| i + 1)
+        .collect())
+}
+
+/// Decrypts `encrypted` and checks it matches `plain`, as [`BuildOptions::check_roundtrip`] does
+/// after encrypting the SA1
+pub fn check_roundtrip(
+    plain: &[u8],
+    encrypted: &[u8],
+    key: &BbAesKey,
+    iv: &BbAesIv,
+    mode: CipherMode,
+) -> Result<(), BBBSError> {
+    if mode.decrypt(encrypted, key, iv, "SA1")? != plain {
+        return Err(BBBSError::RoundtripMismatch);
+    }
+
+    Ok(())
+}
+
+/// Derives the key for the SA at `options.sa_index`, along with its CmdHead; the key is wiped when
+/// it's dropped
+pub fn sa_key_with_options(
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    options: &BuildOptions,
+) -> Result<(CmdHead, Zeroizing<BbAesKey>), BBBSError> {
+    let sa_offset = sa_offset_sized(sksa, options.sk_size, options.sa_index)?;
+    sa_key(sksa, sa_offset, virage2, sk_check, options)
+}
+
+/// Derives the key for the SA at `sa_offset`, using the SHA-1 backend, common IV override and
+/// stage hook from `options`; the returned CmdHead is left as it was read
+fn sa_key(
+    sksa: &[u8],
+    sa_offset: usize,
+    virage2: &[u8],
+    sk_check: SkCheck,
+    options: &BuildOptions,
+) -> Result<(CmdHead, Zeroizing<BbAesKey>), BBBSError> {
+    let sk = split_sk(sksa, options.sk_size)?;
+
+    let cmd = read_cmd_head_at(sksa, sa_offset)?;
+
+    let virage2 = read_virage2(virage2)?;
+
+    if let SkCheck::Bootrom(_) = sk_check {
+        options.stage(BuildStage::DecryptSk);
+    }
+    if !matches!(sk_check, SkCheck::Skip) {
+        options.stage(BuildStage::VerifyHash);
+    }
+    check_sk_hash(sk, &virage2, sk_check, options.sha1())?;
+
+    options.stage(BuildStage::DeriveKey);
+    let start = Instant::now();
+    let sa1_key = Zeroizing::new(derive_sa1_key(
+        &cmd.key,
+        &virage2.boot_app_key,
+        options.common_cmd_iv.as_ref().unwrap_or(&cmd.common_cmd_iv),
+    ));
+    record_stage("SA1 key derive", start, size_of::<BbAesKey>());
+
+    verbose!("derived SA1 key");
+
+    Ok((cmd, sa1_key))
+}
+
+pub fn build_sksa(
+    payload: Vec<u8>,
+    sksa: &[u8],
+    virage2: &[u8],
+    bootrom: &[u8],
+) -> Result<Vec<u8>, BBBSError> {
+    build_sksa_with_options(
+        payload,
+        sksa,
+        virage2,
+        SkCheck::Bootrom(bootrom),
+        &BuildOptions::default(),
+    )
+    .map(|r| r.output)
+}
+
+/// Builds a new SKSA around `payload`. The original SA1 region is never read, so an SKSA of
+/// exactly `SKSA_MIN_BYTES` (SK and SA1 info block only) is accepted and gets a fully generated
+/// SA1
+pub fn build_sksa_with_options(
+    payload: Vec<u8>,
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    options: &BuildOptions,
+) -> Result<BuildReport, BBBSError> {
+    build_sksa_into(payload, sksa, virage2, sk_check, options, memory_output)
+}
+
+/// Like [`build_sksa_with_options`], but writes the output into a buffer from `alloc`, which is
+/// given the final size
+pub fn build_sksa_into<O: AsMut<[u8]>>(
+    payload: Vec<u8>,
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    options: &BuildOptions,
+    alloc: impl FnOnce(usize) -> Result<O, BBBSError>,
+) -> Result<BuildReport<O>, BBBSError> {
+    options.stage(BuildStage::ReadSksa);
+    let sa_offset = sa_offset_sized(sksa, options.sk_size, options.sa_index)?;
+    let (cmd, sa1_key) = sa_key(sksa, sa_offset, virage2, sk_check, options)?;
+    let sk_hash = Some(read_virage2(virage2)?.sk_hash);
+
+    let sa1_iv = options.sa1_iv_from.iv(&cmd);
+    let report = pack_sa(payload, sksa, &cmd, &sa1_key, &sa1_iv, options, alloc)?;
+
+    Ok(BuildReport { sk_hash, ..report })
+}
+
+pub fn build_sksa_with_keys(
+    payload: Vec<u8>,
+    sksa: &[u8],
+    sa1_key: &BbAesKey,
+    sa1_iv: &BbAesIv,
+) -> Result<Vec<u8>, BBBSError> {
+    build_sksa_with_keys_and_options(payload, sksa, sa1_key, sa1_iv, &BuildOptions::default())
+        .map(|r| r.output)
+}
+
+pub fn build_sksa_with_keys_and_options(
+    payload: Vec<u8>,
+    sksa: &[u8],
+    sa1_key: &BbAesKey,
+    sa1_iv: &BbAesIv,
+    options: &BuildOptions,
+) -> Result<BuildReport, BBBSError> {
+    build_sksa_with_keys_into(payload, sksa, sa1_key, sa1_iv, options, memory_output)
+}
+
+pub fn build_sksa_with_keys_into<O: AsMut<[u8]>>(
+    payload: Vec<u8>,
+    sksa: &[u8],
+    sa1_key: &BbAesKey,
+    sa1_iv: &BbAesIv,
+    options: &BuildOptions,
+    alloc: impl FnOnce(usize) -> Result<O, BBBSError>,
+) -> Result<BuildReport<O>, BBBSError> {
+    options.stage(BuildStage::ReadSksa);
+    let cmd = read_cmd_head_at(
+        sksa,
+        sa_offset_sized(sksa, options.sk_size, options.sa_index)?,
+    )?;
+
+    pack_sa(payload, sksa, &cmd, sa1_key, sa1_iv, options, alloc)
+}
+
+fn prebuilt_kind(payload: &[u8], sksa: &[u8], options: &BuildOptions) -> Option<&'static str> {
+    let sk_size = options.sk_size;
+    if payload.len() >= sk_size && payload.get(..sk_size) == sksa.get(..sk_size) {
+        Some("SKSA")
+    } else if payload.get(ENTRYPOINT_OFFSET..ENTRYPOINT_OFFSET + 4)
+        == Some(&options.endian.u32_bytes(UNZIP_BUF_OFFSET))
+    {
+        Some("SA1")
+    } else {
+        None
+    }
+}
+
+fn pack_sa<O: AsMut<[u8]>>(
+    payload: Vec<u8>,
+    sksa: &[u8],
+    cmd: &CmdHead,
+    sa1_key: &BbAesKey,
+    sa1_iv: &BbAesIv,
+    options: &BuildOptions,
+    alloc: impl FnOnce(usize) -> Result<O, BBBSError>,
+) -> Result<BuildReport<O>, BBBSError> {
+    if (cmd.size as usize) < options.header_size {
+        return Err(BBBSError::CmdSizeTooSmall(cmd.size, options.header_size));
+    }
+
+    if payload.is_empty() && !options.allow_empty {
+        return Err(BBBSError::EmptyPayload);
+    }
+
+    #[cfg(feature = "sign")]
+    if options.sign_key.is_some() && options.preserve_tail && !options.sa1_only {
+        return Err(BBBSError::SignedPreservedTail);
+    }
+
+    if !options.allow_prebuilt {
+        if let Some(kind) = prebuilt_kind(&payload, sksa, options) {
+            return Err(BBBSError::PrebuiltPayload(kind));
+        }
+    }
+
+    if let Some(end) = options
+        .extra_payloads
+        .iter()
+        .map(|(offset, data)| offset.saturating_add(data.len()))
+        .find(|&end| end > cmd.size as usize)
+    {
+        return Err(BBBSError::ExtraPayloadTooLong(end, cmd.size));
+    }
+
+    let payload_len = payload.len();
+    let (mut sa1, stored_len) = make_sa1_with_len(payload, options)?;
+
+    if sa1.len() > cmd.size as usize {
+        let payload_start = options.payload_start();
+        let max = (cmd.size as usize).saturating_sub(payload_start) as u32;
+        return Err(if options.compress {
+            BBBSError::CompressedPayloadTooLong(sa1.len() - payload_start, payload_len, max)
+        } else {
+            BBBSError::PayloadTooLong(payload_len, max)
+        });
+    }
+
+    let padding = cmd.size as usize - sa1.len();
+    sa1.resize(cmd.size as _, options.pad_byte);
+
+    if !sa1.len().is_multiple_of(AES_BLOCK_SIZE) {
+        return Err(BBBSError::SA1NotBlockAligned(sa1.len()));
+    }
+
+    let sa1_hash = options.sha1().digest(&sa1);
+
+    options.stage(BuildStage::EncryptSa1);
+    let start = Instant::now();
+    let sa1_enc = if options.progress {
+        encrypt_with_progress(&sa1, sa1_key, sa1_iv, options.cipher_mode, "SA1")?
+    } else {
+        options.cipher_mode.encrypt(&sa1, sa1_key, sa1_iv, "SA1")?
+    };
+    record_stage("encrypt", start, sa1.len());
+
+    verbose!("encrypted SA1 (0x{:X} bytes)", sa1_enc.len());
+
+    if options.check_roundtrip {
+        check_roundtrip(&sa1, &sa1_enc, sa1_key, sa1_iv, options.cipher_mode)?;
+    }
+
+    let prefix = if options.sa1_only {
+        &[][..]
+    } else {
+        &sksa[0..sa_offset_sized(sksa, options.sk_size, options.sa_index)? + SA1_INFO_BLOCK_SIZE]
+    };
+    let tail = if options.preserve_tail && !options.sa1_only {
+        sksa.get(prefix.len() + sa1_enc.len()..).unwrap_or_default()
+    } else {
+        &[]
+    };
+
+    #[cfg(feature = "sign")]
+    let signature = match &options.sign_key {
+        Some(key) => sign_sa1(key, &sa1_enc, options.sha1())?,
+        None => vec![],
+    };
+    #[cfg(not(feature = "sign"))]
+    let signature = vec![];
+    if !signature.is_empty() {
+        verbose!("signed SA1 (0x{:X} byte signature)", signature.len());
+    }
+
+    options.stage(BuildStage::Write);
+    let mut output = alloc(prefix.len() + sa1_enc.len() + signature.len() + tail.len())?;
+    let (head, rest) = output.as_mut().split_at_mut(prefix.len());
+    let (sa, rest) = rest.split_at_mut(sa1_enc.len());
+    let (sig, rest) = rest.split_at_mut(signature.len());
+    head.copy_from_slice(prefix);
+    sa.copy_from_slice(&sa1_enc);
+    sig.copy_from_slice(&signature);
+    rest.copy_from_slice(tail);
+
+    Ok(BuildReport {
+        output,
+        sk_hash: None,
+        sa1_hash,
+        payload_len,
+        stored_len,
+        padded_len: sa1.len(),
+        padding,
+        entrypoint: options.entrypoint,
+        plain_sa1: sa1,
+    })
+}
+
+fn decrypt_sa(
+    sksa: &[u8],
+    sa_offset: usize,
+    cmd: &CmdHead,
+    sa_key: &BbAesKey,
+    sa_iv: &BbAesIv,
+    mode: CipherMode,
+) -> Result<Vec<u8>, BBBSError> {
+    let sa_start = sa_offset + SA1_INFO_BLOCK_SIZE;
+    let sa_end = sa_start + cmd.size as usize;
+    if sksa.len() == sa_start && cmd.size != 0 {
+        return Err(BBBSError::MissingSA1Region(sksa.len()));
+    }
+    if sksa.len() < sa_end {
+        return Err(BBBSError::SATooShort(sksa.len(), sa_end));
+    }
+
+    mode.decrypt(&sksa[sa_start..sa_end], sa_key, sa_iv, "SA1")
+}
+
+pub fn decrypt_sa1(sksa: &[u8], virage2: &[u8], sk_check: SkCheck) -> Result<Vec<u8>, BBBSError> {
+    decrypt_sa1_with_mode(sksa, virage2, sk_check, CipherMode::Cbc)
+}
+
+/// Like [`decrypt_sa1`], for SA1s encrypted with `mode`
+pub fn decrypt_sa1_with_mode(
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    mode: CipherMode,
+) -> Result<Vec<u8>, BBBSError> {
+    let options = BuildOptions {
+        cipher_mode: mode,
+        ..Default::default()
+    };
+    decrypt_sa_with_options(sksa, virage2, sk_check, &options)
+}
+
+/// Like [`decrypt_sa1`], but decrypts the SA at `options.sa_index` after an SK of
+/// `options.sk_size` bytes, deriving its key and decrypting it the way a build with `options` would
+/// have encrypted it
+pub fn decrypt_sa_with_options(
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    options: &BuildOptions,
+) -> Result<Vec<u8>, BBBSError> {
+    let sa_offset = sa_offset_sized(sksa, options.sk_size, options.sa_index)?;
+    let (cmd, sa_key) = sa_key(sksa, sa_offset, virage2, sk_check, options)?;
+
+    let sa_iv = options.sa1_iv_from.iv(&cmd);
+    decrypt_sa(sksa, sa_offset, &cmd, &sa_key, &sa_iv, options.cipher_mode)
+}
+
+/// Checks that the SA in `sksa` is `payload` laid out by [`make_sa1`] with `options` and padded to
+/// the CmdHead's size; the ROM header isn't compared, so the entrypoint doesn't have to match
+pub fn validate_sksa(
+    sksa: &[u8],
+    payload: Vec<u8>,
+    virage2: &[u8],
+    sk_check: SkCheck,
+    options: &BuildOptions,
+) -> Result<(), BBBSError> {
+    let sa1 = decrypt_sa_with_options(sksa, virage2, sk_check, options)?;
+
+    let mut expected = make_sa1(payload, options)?;
+    if expected.len() < sa1.len() {
+        expected.resize(sa1.len(), options.pad_byte);
+    }
+
+    let len = sa1.len().max(expected.len());
+    match (options.header_size..len).find(|&i| sa1.get(i) != expected.get(i)) {
+        Some(i) => Err(BBBSError::PayloadMismatch(i)),
+        None => Ok(()),
+    }
+}
+
+/// Decrypts the existing SA at `options.sa_index` the way [`decrypt_sa_with_options`] does and
+/// checks its plaintext hash against `expected`
+pub fn check_sa_hash(
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    options: &BuildOptions,
+    expected: &BbShaHash,
+) -> Result<(), BBBSError> {
+    let sa = decrypt_sa_with_options(sksa, virage2, sk_check, options)?;
+
+    let hash = options.sha1().digest(&sa);
+    if !bool::from(hash.ct_eq(expected)) {
+        return Err(BBBSError::InvalidSA1Hash(hash.to_hex(), expected.to_hex()));
+    }
+
+    Ok(())
+}
+
+pub fn recrypt_sksa(
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    target_virage2: &[u8],
+    target_sk_check: SkCheck,
+) -> Result<Vec<u8>, BBBSError> {
+    recrypt_sksa_sized(
+        sksa,
+        SK_SIZE,
+        virage2,
+        sk_check,
+        target_virage2,
+        target_sk_check,
+    )
+}
+
+/// Like [`recrypt_sksa`], for an SK of `sk_size` bytes
+pub fn recrypt_sksa_sized(
+    sksa: &[u8],
+    sk_size: usize,
+    virage2: &[u8],
+    sk_check: SkCheck,
+    target_virage2: &[u8],
+    target_sk_check: SkCheck,
+) -> Result<Vec<u8>, BBBSError> {
+    let options = BuildOptions {
+        sk_size,
+        ..Default::default()
+    };
+    recrypt_sksa_with_options(
+        sksa,
+        virage2,
+        sk_check,
+        target_virage2,
+        target_sk_check,
+        &options,
+    )
+}
+
+/// Like [`recrypt_sksa_sized`], but re-encrypts the SA at `options.sa_index`, decrypting and
+/// re-encrypting it with `options.cipher_mode` and the IV from `options.sa1_iv_from`, so it's
+/// encrypted the same way for the new keys; everything outside the SA is kept
+pub fn recrypt_sksa_with_options(
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    target_virage2: &[u8],
+    target_sk_check: SkCheck,
+    options: &BuildOptions,
+) -> Result<Vec<u8>, BBBSError> {
+    let sa_offset = sa_offset_sized(sksa, options.sk_size, options.sa_index)?;
+    let (cmd, key) = sa_key(sksa, sa_offset, virage2, sk_check, options)?;
+    let iv = options.sa1_iv_from.iv(&cmd);
+    let sa = decrypt_sa(sksa, sa_offset, &cmd, &key, &iv, options.cipher_mode)?;
+
+    let (cmd, key) = sa_key(sksa, sa_offset, target_virage2, target_sk_check, options)?;
+
+    let iv = options.sa1_iv_from.iv(&cmd);
+    let sa_enc = options.cipher_mode.encrypt(&sa, &key, &iv, "SA1")?;
+
+    let sa_start = sa_offset + SA1_INFO_BLOCK_SIZE;
+    let mut rv = sksa.to_vec();
+    rv[sa_start..sa_start + sa_enc.len()].copy_from_slice(&sa_enc);
+
+    Ok(rv)
+}
+
+/// Decrypts the SA at `options.sa_index`, overwrites the bytes at each of `patches` (offsets are
+/// from the start of the SA) and encrypts it again with the same key and IV; everything outside the
+/// SA is kept
+pub fn patch_sksa(
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck,
+    patches: &[(usize, Vec<u8>)],
+    options: &BuildOptions,
+) -> Result<Vec<u8>, BBBSError> {
+    let sa_offset = sa_offset_sized(sksa, options.sk_size, options.sa_index)?;
+    let (cmd, sa_key) = sa_key(sksa, sa_offset, virage2, sk_check, options)?;
+    let sa_iv = options.sa1_iv_from.iv(&cmd);
+    let mut sa1 = decrypt_sa(sksa, sa_offset, &cmd, &sa_key, &sa_iv, options.cipher_mode)?;
+
+    for (offset, bytes) in patches {
+        let end = offset.saturating_add(bytes.len());
+        if end > sa1.len() {
+            return Err(BBBSError::PatchOutOfBounds(*offset, bytes.len(), sa1.len()));
+        }
+        sa1[*offset..end].copy_from_slice(bytes);
+    }
+
+    let sa1_enc = options.cipher_mode.encrypt(&sa1, &sa_key, &sa_iv, "SA1")?;
+
+    let sa_start = sa_offset + SA1_INFO_BLOCK_SIZE;
+    let mut rv = sksa.to_vec();
+    rv[sa_start..sa_start + sa1_enc.len()].copy_from_slice(&sa1_enc);
+
+    Ok(rv)
+}
+
+pub fn slice_dump<'a>(
+    dump: &'a [u8],
+    offset: Option<usize>,
+    size: usize,
+    name: &'static str,
+) -> Result<&'a [u8], BBBSError> {
+    match offset {
+        Some(offset) if dump.len() > size => dump
+            .get(offset..offset.saturating_add(size))
+            .ok_or(BBBSError::RegionOutOfBounds(offset, size, name, dump.len())),
+        _ => Ok(dump),
+    }
+}
+
+fn read_bundle(bundle: Option<&IOType>) -> Result<Bundle> {
+    match bundle {
+        Some(bundle) => Ok(Bundle::read_from_buf(&bundle.read()?)?),
+        None => Ok(Bundle::default()),
+    }
+}
+
+fn read_keys(keys: &KeyArgs) -> Result<(Vec<u8>, Option<Vec<u8>>)> {
+    let start = Instant::now();
+    let bundle = read_bundle(keys.bundle.as_ref())?;
+
+    let virage2 = match &keys.virage2 {
+        Some(virage2) => virage2.read()?,
+        None => bundle.virage2.ok_or(BBBSError::MissingInput("Virage2"))?,
+    };
+    let virage2 = slice_dump(&virage2, keys.virage2_offset, Virage2::SIZE, "Virage2")?.to_vec();
+
+    let bootrom = match &keys.bootrom {
+        Some(bootrom) => Some(bootrom.read()?),
+        None => bundle.bootrom,
+    };
+    let bootrom = match bootrom {
+        Some(bootrom) => {
+            Some(slice_dump(&bootrom, keys.bootrom_offset, BOOTROM_SIZE, "bootrom")?.to_vec())
+        }
+        None => None,
+    };
+
+    record_stage(
+        "read",
+        start,
+        virage2.len() + bootrom.as_ref().map_or(0, Vec::len),
+    );
+
+    Ok((virage2, bootrom))
+}
+
+fn sk_check<'a>(keys: &KeyArgs, bootrom: &'a Option<Vec<u8>>) -> Result<SkCheck<'a>> {
+    if keys.no_verify {
+        return Ok(SkCheck::Skip);
+    }
+
+    match (keys.sk_hash, bootrom) {
+        (Some(sk_hash), _) => Ok(SkCheck::Hash(sk_hash)),
+        (None, Some(bootrom)) => Ok(SkCheck::Bootrom(bootrom)),
+        (None, None) => bail!("a bootrom is required unless --sk-hash or --no-verify is given"),
+    }
+}
+
+/// Checks the SK hash up front, only warning on a mismatch, and returns a check that skips it from
+/// then on
+fn ignore_sk_hash<'a>(
+    sksa: &[u8],
+    virage2: &[u8],
+    sk_check: SkCheck<'a>,
+    options: &BuildOptions,
+) -> Result<SkCheck<'a>, BBBSError> {
+    let sk = split_sk(sksa, options.sk_size)?;
+
+    match check_sk_hash(sk, &read_virage2(virage2)?, sk_check, options.sha1()) {
+        Err(BBBSError::InvalidSKHash(got, expected)) => warning!(
+            "SK hash mismatch, continuing because of --ignore-sk-hash\n  got:      {got}\n  expected: {expected}"
+        ),
+        result => result?,
+    }
+
+    Ok(SkCheck::Skip)
+}
+
+/// Reads the whole SKSA, as building checks every SA for a reused IV
+fn read_sksa_input(sksa: &SksaInput, options: &BuildOptions) -> Result<Vec<u8>> {
+    match sksa {
+        SksaInput::Combined(sksa) => Ok(sksa.read()?),
+        SksaInput::Bundle(bundle) => Ok(read_bundle(Some(bundle))?
+            .sksa
+            .ok_or(BBBSError::MissingInput("SKSA"))?),
+        SksaInput::Parts { sk, cmd } => {
+            Ok(assemble_sksa(&sk.read()?, &cmd.read()?, options.sk_size)?)
+        }
+    }
+}
+
+/// Warns if the rebuilt SA's plaintext hash isn't the one in its CmdHead, which is kept as it was
+fn check_cmd_hash(sksa: &[u8], options: &BuildOptions, hash: &BbShaHash) -> Result<(), BBBSError> {
+    let sa_offset = sa_offset_sized(sksa, options.sk_size, options.sa_index)?;
+    let cmd = read_cmd_head_at(sksa, sa_offset)?;
+    if cmd.hash != *hash {
+        warning!(
+            "SA{} hash {} doesn't match the one in its CmdHead ({}), which is left as it was",
+            options.sa_index,
+            hash.to_hex(),
+            cmd.hash.to_hex()
+        );
+    }
+
+    Ok(())
+}
+
+/// Warns (or with `strict`, fails) if the SA being built would share its key and IV with another
+fn check_iv_reuse(sksa: &[u8], options: &BuildOptions, strict: bool) -> Result<(), BBBSError> {
+    let (sk_size, sa_index) = (options.sk_size, options.sa_index);
+    if let Some(&other) = reused_iv_sas(sksa, sk_size, sa_index, options.sa1_iv_from)?.first() {
+        if strict {
+            return Err(BBBSError::IvReuse(sa_index, other));
+        }
+        warning!("{}", BBBSError::IvReuse(sa_index, other));
+    }
+    Ok(())
+}
+
+fn check_expected_sk(
+    keys: &KeyArgs,
+    sksa: &[u8],
+    sk_size: usize,
+    bootrom: &Option<Vec<u8>>,
+) -> Result<()> {
+    let Some(expect_sk) = &keys.expect_sk else {
+        return Ok(());
+    };
+    // the SK can only be decrypted to compare against with the bootrom
+    let bootrom = bootrom.as_ref().ok_or(BBBSError::MissingInput(
+        "bootrom to decrypt the SK for --expect-sk",
+    ))?;
+
+    compare_sk_sized(sksa, sk_size, bootrom, &expect_sk.read()?)?;
+    verbose!("SK matched {}", expect_sk);
+    Ok(())
+}
+
+fn check_overwrite<'a>(
+    outfile: &IOType,
+    mut inputs: impl Iterator<Item = &'a IOType>,
+) -> Result<(), BBBSError> {
+    match outfile.canonical_path() {
+        Some(out) if inputs.any(|i| i.canonical_path().as_ref() == Some(&out)) => {
+            Err(BBBSError::WouldOverwriteInput(out))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reads the stub and extra payloads from `layout` into `options`
+fn read_layout(layout: &LayoutArgs, options: &mut BuildOptions) -> Result<()> {
+    if let Some(stub) = &layout.sa1_stub {
+        options.stub = stub.read()?;
+    }
+
+    for (offset, file) in &layout.extra_payloads {
+        options.extra_payloads.push((*offset, file.read()?));
+    }
+
+    Ok(())
+}
+
+/// Turns the payload as read into the bytes to pack, taking the entrypoint from an ELF unless one
+/// was given
+fn convert_payload(
+    layout: &LayoutArgs,
+    payload: Vec<u8>,
+    options: &mut BuildOptions,
+) -> Result<Vec<u8>> {
+    match layout.input_format {
+        InputFormat::Raw => Ok(payload),
+        InputFormat::Elf => {
+            let elf = crate::elf::flatten(&payload)?;
+            verbose!(
+                "flattened ELF (0x{:X} bytes from 0x{:X}, entrypoint 0x{:08X})",
+                elf.data.len(),
+                elf.base,
+                elf.entrypoint
+            );
+            if !layout.explicit_entrypoint {
+                options.entrypoint = elf.entrypoint;
+            }
+            Ok(elf.data)
+        }
+    }
+}
+
+pub fn build(mut args: BuildArgs) -> Result<()> {
+    read_layout(&args.layout, &mut args.options)?;
+
+    #[cfg(feature = "sign")]
+    if let Some(key) = &args.sign_key {
+        args.options.sign_key = Some(Arc::new(parse_sign_key(&key.read_string()?)?));
+    }
+
+    if args.max_payload {
+        let sksa = read_sksa_input(&args.sksa, &args.options)?;
+        let sa_offset = sa_offset_sized(&sksa, args.options.sk_size, args.options.sa_index)?;
+        let cmd = read_cmd_head_at(&sksa, sa_offset)?;
+        let max = (cmd.size as usize).saturating_sub(args.options.payload_start());
+        println!("{max} (0x{max:X})");
+        return Ok(());
+    }
+
+    if !args.force {
+        let inputs = std::iter::once(&args.infile)
+            .chain(args.sksa.inputs())
+            .chain(args.keys.inputs());
+        check_overwrite(&args.outfile, inputs)?;
+    }
+
+    let start = Instant::now();
+    let infile = args
+        .infile
+        .read_with_capacity(args.stdin_len.unwrap_or_default())?;
+
+    verbose!("read payload (0x{:X} bytes)", infile.len());
+
+    if let Some(expected) = &args.payload_sha1 {
+        let hash = sha1(&infile);
+        if !bool::from(hash.ct_eq(expected)) {
+            return Err(BBBSError::PayloadHashMismatch(hash.to_hex(), expected.to_hex()).into());
+        }
+        verbose!("payload hash matched");
+    }
+
+    let infile = convert_payload(&args.layout, infile, &mut args.options)?;
+
+    let sksa = read_sksa_input(&args.sksa, &args.options)?;
+
+    verbose!("read SKSA (0x{:X} bytes)", sksa.len());
+    record_stage("read", start, infile.len() + sksa.len());
+
+    check_iv_reuse(&sksa, &args.options, args.strict)?;
+
+    let mmap = args.mmap && !args.dry_run;
+    let alloc = |len| {
+        if mmap {
+            if let Some(map) = args.outfile.map_output(len).map_err(BBBSError::MapOutput)? {
+                return Ok(Output::Mapped(map));
+            }
+        }
+        Ok(Output::Memory(vec![0; len]))
+    };
+
+    let keys = match args.sa1_key.is_some() && args.keys.no_verify {
+        true => None,
+        false => Some(read_keys(&args.keys)?),
+    };
+
+    let report = match (args.sa1_key, &keys) {
+        (Some((sa1_key, sa1_iv)), None) => {
+            verbose!("using SA1 key and IV from the command line");
+            build_sksa_with_keys_into(infile, &sksa, &sa1_key, &sa1_iv, &args.options, alloc)?
+        }
+        (Some((sa1_key, sa1_iv)), Some((virage2, bootrom))) => {
+            check_expected_sk(&args.keys, &sksa, args.options.sk_size, bootrom)?;
+            let mut sk_check = sk_check(&args.keys, bootrom)?;
+            if args.ignore_sk_hash {
+                sk_check = ignore_sk_hash(&sksa, virage2, sk_check, &args.options)?;
+            }
+            check_sk_hash(
+                split_sk(&sksa, args.options.sk_size)?,
+                &read_virage2(virage2)?,
+                sk_check,
+                args.options.sha1(),
+            )?;
+
+            verbose!("using SA1 key and IV from the command line");
+            let report =
+                build_sksa_with_keys_into(infile, &sksa, &sa1_key, &sa1_iv, &args.options, alloc)?;
+            BuildReport {
+                sk_hash: Some(read_virage2(virage2)?.sk_hash),
+                ..report
+            }
+        }
+        (None, Some((virage2, bootrom))) => {
+            check_expected_sk(&args.keys, &sksa, args.options.sk_size, bootrom)?;
+            let mut sk_check = sk_check(&args.keys, bootrom)?;
+            if args.ignore_sk_hash {
+                sk_check = ignore_sk_hash(&sksa, virage2, sk_check, &args.options)?;
+            }
+
+            if let Some(expected) = &args.expect_sa1_hash {
+                check_sa_hash(&sksa, virage2, sk_check, &args.options, expected)?;
+                verbose!("input SA1 hash matched");
+            }
+
+            build_sksa_into(infile, &sksa, virage2, sk_check, &args.options, alloc)?
+        }
+        (None, None) => unreachable!("keys are only skipped with --sa1-key"),
+    };
+
+    check_cmd_hash(&sksa, &args.options, &report.sa1_hash)?;
+
+    if args.print_sa1_hash {
+        eprintln!("{}", report.sa1_hash.to_hex());
+    }
+
+    if args.crc {
+        eprintln!("CRC32: {:08X}", crc32fast::hash(report.output.as_ref()));
+    }
+
+    if args.print_padding {
+        eprintln!("SA1 padding: 0x{:X} bytes", report.padding);
+    }
+
+    if let Some(compare) = &args.compare {
+        let reference = compare.read()?;
+        let ranges = diff_ranges(report.output.as_ref(), &reference);
+        if ranges.is_empty() {
+            eprintln!("Output is identical to {compare}");
+        }
+        for (offset, len) in ranges {
+            eprintln!("0x{offset:08X} +0x{len:X}");
+        }
+    }
+
+    if let Some(previous) = &args.cbc_common_prefix {
+        match first_changed_block(&previous.read()?, &report.plain_sa1) {
+            Some(block) => eprintln!(
+                "First changed SA1 block: {block} (offset 0x{:X}); 0x{:X} of 0x{:X} ciphertext bytes differ",
+                block * AES_BLOCK_SIZE,
+                report.plain_sa1.len().saturating_sub(block * AES_BLOCK_SIZE),
+                report.plain_sa1.len()
+            ),
+            None => eprintln!("Plaintext SA1 is identical to {previous}"),
+        }
+    }
+
+    if args.dry_run {
+        eprintln!(
+            "Would write 0x{:X} bytes to {} (SA{}, 0x{:X} bytes of padding)",
+            report.output.as_ref().len(),
+            args.outfile,
+            args.options.sa_index,
+            report.padding
+        );
+        return Ok(());
+    }
+
+    if let Some(dir) = &args.dump_all {
+        dump_all(dir, &args, keys.as_ref(), &sksa, &report)?;
+    }
+
+    let start = Instant::now();
+    match &report.output {
+        Output::Memory(output) => write_output(&args.outfile, output)?,
+        Output::Mapped(map) => {
+            map.finish()?;
+            report_written(map.as_ref().len(), &args.outfile);
+        }
+    }
+    record_stage("write", start, report.output.as_ref().len());
+
+    verbose!("wrote output");
+
+    if let (true, IOType::File(path)) = (args.verify_write, &args.outfile) {
+        let written = std::fs::read(path)?;
+        let output = report.output.as_ref();
+        if written != output {
+            let offset = written
+                .iter()
+                .zip(output)
+                .position(|(a, b)| a != b)
+                .unwrap_or(written.len().min(output.len()));
+            return Err(BBBSError::WriteVerifyFailed(path.clone(), offset).into());
+        }
+        verbose!("read back {} and it matched", args.outfile);
+    }
+
+    if let Some(dump) = &args.dump_plain_sa1 {
+        write_output(dump, &report.plain_sa1)?;
+    }
+
+    if let Some(hash_file) = &args.output_hash_file {
+        hash_file.write(format!("{}\n", sha1(report.output.as_ref()).to_hex()))?;
+    }
+
+    if let Some(json) = &args.json {
+        let summary = json!({
+            "output_size": report.output.as_ref().len(),
+            "sa1_size": report.padded_len,
+            "padding_bytes": report.padding,
+            "sk_hash": report.sk_hash.map(|h| h.to_hex()),
+            "sa1_hash": report.sa1_hash.to_hex(),
+            "entrypoint": report.entrypoint,
+        });
+        json.write(format!("{summary}\n"))?;
+    }
+
+    if let Some(manifest) = &args.manifest {
+        let sa_offset = sa_offset_sized(&sksa, args.options.sk_size, args.options.sa_index)?;
+        let layout = manifest_json(&report, &args.options, sa_offset);
+        manifest.write(format!("{layout:#}\n"))?;
+    }
+
+    Ok(())
+}
+
+/// Describes where each region of a built SKSA is, for tools that would otherwise re-parse it;
+/// with `sa1_only` there's no SK or info block, and the SA1 starts the output
+fn manifest_json<O: AsRef<[u8]>>(
+    report: &BuildReport<O>,
+    options: &BuildOptions,
+    sa_offset: usize,
+) -> serde_json::Value {
+    let region = |offset: usize, size: usize| json!({ "offset": offset, "size": size });
+
+    let (sk, info, sa1_offset) = match options.sa1_only {
+        true => (None, None, 0),
+        false => (
+            Some(region(0, options.sk_size)),
+            Some(region(sa_offset, SA1_INFO_BLOCK_SIZE)),
+            sa_offset + SA1_INFO_BLOCK_SIZE,
+        ),
+    };
+    let output = report.output.as_ref();
+    let payload_start = sa1_offset + options.payload_start();
+    let mut extra: Vec<_> = options.extra_payloads.iter().collect();
+    extra.sort_by_key(|(offset, _)| *offset);
+    let extra: Vec<_> = extra
+        .into_iter()
+        .map(|(offset, data)| region(sa1_offset + offset, data.len()))
+        .collect();
+
+    json!({
+        "size": output.len(),
+        "sk": sk,
+        "info_block": info,
+        "sa_index": options.sa_index,
+        "sa1": region(sa1_offset, report.padded_len),
+        "payload": region(payload_start, report.stored_len),
+        "extra_payloads": extra,
+        "padding_bytes": report.padding,
+        "entrypoint": report.entrypoint,
+        "hashes": {
+            "sk": report.sk_hash.map(|h| h.to_hex()),
+            "sa1": report.sa1_hash.to_hex(),
+            "output": sha1(output).to_hex(),
+        },
+    })
+}
+
+fn cmd_head_json(cmd: &CmdHead) -> serde_json::Value {
+    json!({
+        "ca_crl_version": cmd.ca_crl_version,
+        "cp_crl_version": cmd.cp_crl_version,
+        "size": cmd.size,
+        "desc_flags": cmd.desc_flags,
+        "common_cmd_iv": cmd.common_cmd_iv.to_hex(),
+        "hash": cmd.hash.to_hex(),
+        "iv": cmd.iv.to_hex(),
+        "exec_flags": cmd.exec_flags,
+        "hw_access_rights": cmd.hw_access_rights,
+        "secure_kernel_rights": cmd.secure_kernel_rights,
+        "bbid": cmd.bbid,
+        "issuer": String::from_utf8_lossy(&cmd.issuer).trim_end_matches('\0'),
+        "id": cmd.id,
+        "key": cmd.key.to_hex(),
+        "content_meta_data_sign": cmd.content_meta_data_sign.to_hex(),
+    })
+}
+
+/// Writes each intermediate artifact of a build to `dir`; the decrypted SK is only written if a
+/// bootrom was given, and the SA1 key only if it was given or could be derived
+fn dump_all<O: AsRef<[u8]>>(
+    dir: &Path,
+    args: &BuildArgs,
+    keys: Option<&(Vec<u8>, Option<Vec<u8>>)>,
+    sksa: &[u8],
+    report: &BuildReport<O>,
+) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let dump = |name: &str, data: &[u8]| write_output(&IOType::File(dir.join(name)), data);
+
+    let sk_size = args.options.sk_size;
+    let sa_offset = sa_offset_sized(sksa, sk_size, args.options.sa_index)?;
+    let cmd = read_cmd_head_at(sksa, sa_offset)?;
+
+    dump(
+        "cmd_head.json",
+        format!("{:#}\n", cmd_head_json(&cmd)).as_bytes(),
+    )?;
+    dump("plain_sa1.bin", &report.plain_sa1)?;
+    dump("output.bin", report.output.as_ref())?;
+
+    if !args.dump_keys {
+        return Ok(());
+    }
+
+    if let Some((_, Some(bootrom))) = keys {
+        dump("sk.bin", &decrypt_sk(split_sk(sksa, sk_size)?, bootrom)?)?;
+    }
+
+    let sa1_key = match (&args.sa1_key, keys) {
+        (Some((key, iv)), _) => Some((Zeroizing::new(*key), *iv)),
+        (None, Some((virage2, _))) => {
+            let (_, key) = sa_key(sksa, sa_offset, virage2, SkCheck::Skip, &args.options)?;
+            Some((key, args.options.sa1_iv_from.iv(&cmd)))
+        }
+        (None, None) => None,
+    };
+    if let Some((key, iv)) = sa1_key {
+        let text = Zeroizing::new(format!("key: {}\niv:  {}\n", key.to_hex(), iv.to_hex()));
+        dump("sa1_key.txt", text.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Returns the length of `payload` with its trailing zero padding removed, keeping at least
+/// `min_size` bytes; if `aligned`, the length is rounded up to the end of the last non-zero AES
+/// block
+pub fn trimmed_len(payload: &[u8], min_size: usize, aligned: bool) -> usize {
+    let mut len = payload.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    if aligned {
+        len = len.next_multiple_of(AES_BLOCK_SIZE);
+    }
+
+    len.max(min_size).min(payload.len())
+}
+
+pub fn extract(args: ExtractArgs) -> Result<()> {
+    if !args.force {
+        let inputs = std::iter::once(&args.infile).chain(args.keys.inputs());
+        check_overwrite(&args.outfile, inputs)?;
+    }
+
+    let sksa = args.infile.read()?;
+
+    let (virage2, bootrom) = read_keys(&args.keys)?;
+    check_expected_sk(&args.keys, &sksa, args.sk_size, &bootrom)?;
+    let sk_check = sk_check(&args.keys, &bootrom)?;
+
+    let options = BuildOptions {
+        sk_size: args.sk_size,
+        sa1_iv_from: args.sa1_iv_from,
+        cipher_mode: args.cipher_mode,
+        ..Default::default()
+    };
+    let sa1 = decrypt_sa_with_options(&sksa, &virage2, sk_check, &options)?;
+
+    let payload_start = args.header_size + args.payload_offset;
+    let mut payload = sa1[payload_start.min(sa1.len())..].to_vec();
+
+    if args.trim {
+        payload.truncate(trimmed_len(&payload, args.min_size, args.trim_aligned));
+    }
+
+    write_output(&args.outfile, payload)?;
+
+    Ok(())
+}
+
+pub fn recrypt(args: RecryptArgs) -> Result<()> {
+    if !args.force {
+        let inputs = std::iter::once(&args.infile)
+            .chain(args.keys.inputs())
+            .chain(args.target_keys.inputs());
+        check_overwrite(&args.outfile, inputs)?;
+    }
+
+    let sksa = args.infile.read()?;
+
+    let (virage2, bootrom) = read_keys(&args.keys)?;
+    let (target_virage2, target_bootrom) = read_keys(&args.target_keys)?;
+    check_expected_sk(&args.keys, &sksa, args.sk_size, &bootrom)?;
+
+    let options = BuildOptions {
+        sk_size: args.sk_size,
+        sa_index: args.sa_index,
+        sa1_iv_from: args.sa1_iv_from,
+        cipher_mode: args.cipher_mode,
+        common_cmd_iv: args.common_cmd_iv,
+        ..Default::default()
+    };
+    let outfile = recrypt_sksa_with_options(
+        &sksa,
+        &virage2,
+        sk_check(&args.keys, &bootrom)?,
+        &target_virage2,
+        sk_check(&args.target_keys, &target_bootrom)?,
+        &options,
+    )?;
+
+    write_output(&args.outfile, outfile)?;
+
+    Ok(())
+}
+
+pub fn patch(args: PatchArgs) -> Result<()> {
+    if !args.force {
+        let inputs = std::iter::once(&args.infile).chain(args.keys.inputs());
+        check_overwrite(&args.outfile, inputs)?;
+    }
+
+    let sksa = args.infile.read()?;
+
+    let (virage2, bootrom) = read_keys(&args.keys)?;
+    check_expected_sk(&args.keys, &sksa, args.sk_size, &bootrom)?;
+    let sk_check = sk_check(&args.keys, &bootrom)?;
+
+    let options = BuildOptions {
+        sk_size: args.sk_size,
+        sa1_iv_from: args.sa1_iv_from,
+        cipher_mode: args.cipher_mode,
+        ..Default::default()
+    };
+    let outfile = patch_sksa(&sksa, &virage2, sk_check, &args.patches, &options)?;
+
+    let patched = decrypt_sa_with_options(&outfile, &virage2, sk_check, &options)?;
+    check_cmd_hash(&outfile, &options, &options.sha1().digest(&patched))?;
+
+    write_output(&args.outfile, outfile)?;
+
+    Ok(())
+}
+
+pub fn verify(args: VerifyArgs) -> Result<()> {
+    if args.keys.no_verify {
+        bail!("--no-verify cannot be used with verify");
+    }
+
+    let sksa = args.sksa.read_prefix(args.sk_size + SA1_INFO_BLOCK_SIZE)?;
+
+    let (virage2, bootrom) = read_keys(&args.keys)?;
+    check_expected_sk(&args.keys, &sksa, args.sk_size, &bootrom)?;
+    let sk_check = sk_check(&args.keys, &bootrom)?;
+
+    verify_sk_sized(&sksa, args.sk_size, &virage2, sk_check)?;
+
+    println!("SK hash OK");
+
+    Ok(())
+}
+
+pub fn validate(mut args: ValidateArgs) -> Result<()> {
+    read_layout(&args.layout, &mut args.options)?;
+
+    let sksa = args.sksa.read()?;
+    let payload = convert_payload(&args.layout, args.payload.read()?, &mut args.options)?;
+
+    let (virage2, bootrom) = read_keys(&args.keys)?;
+    check_expected_sk(&args.keys, &sksa, args.options.sk_size, &bootrom)?;
+    let sk_check = sk_check(&args.keys, &bootrom)?;
+
+    validate_sksa(&sksa, payload, &virage2, sk_check, &args.options)?;
+
+    println!("SA1 matches {}", args.payload);
+
+    Ok(())
+}
+
+pub fn info(args: InfoArgs) -> Result<()> {
+    let sksa = args.sksa.read_prefix(args.sk_size + SA1_INFO_BLOCK_SIZE)?;
+
+    let cmd = read_cmd_head_sized(&sksa, args.sk_size)?;
+
+    println!("size:          0x{:08X}", cmd.size);
+    println!(
+        "max payload:   0x{:08X}",
+        cmd.size.saturating_sub(ROM_HEADER_SIZE as u32)
+    );
+    println!("desc flags:    0x{:08X}", cmd.desc_flags);
+    println!("exec flags:    0x{:08X}", cmd.exec_flags);
+    println!("content ID:    0x{:08X}", cmd.id);
+    println!("BBID:          0x{:08X}", cmd.bbid);
+    println!("common cmd IV: {}", cmd.common_cmd_iv.to_hex());
+    println!("IV:            {}", cmd.iv.to_hex());
+    println!("key:           {}", cmd.key.to_hex());
+    println!("hash:          {}", cmd.hash.to_hex());
+
+    Ok(())
+}
+
+pub fn list(args: ListArgs) -> Result<()> {
+    let sksa = args.sksa.read()?;
+
+    let min = args.sk_size + SA1_INFO_BLOCK_SIZE;
+    if sksa.len() < min {
+        return Err(BBBSError::SKSATooShort(sksa.len(), min).into());
+    }
+
+    println!("SA  offset      size");
+    for (i, (offset, cmd)) in list_sas_sized(&sksa, args.sk_size)?.into_iter().enumerate() {
+        let end = offset + SA1_INFO_BLOCK_SIZE + cmd.size as usize;
+        let truncated = if end > sksa.len() { " (truncated)" } else { "" };
+        println!("{:<3} 0x{offset:08X}  0x{:08X}{truncated}", i + 1, cmd.size);
+    }
+
+    Ok(())
+}
+
+pub fn keys(args: KeysArgs) -> Result<()> {
+    if !args.dump_keys {
+        bail!("refusing to print key material without --dump-keys");
+    }
+
+    let sksa = args.sksa.read_prefix(args.sk_size + SA1_INFO_BLOCK_SIZE)?;
+
+    let (virage2, bootrom) = read_keys(&args.keys)?;
+    check_expected_sk(&args.keys, &sksa, args.sk_size, &bootrom)?;
+    let sk_check = sk_check(&args.keys, &bootrom)?;
+
+    let options = BuildOptions {
+        sk_size: args.sk_size,
+        common_cmd_iv: args.common_cmd_iv,
+        ..Default::default()
+    };
+    let (cmd, sa1_key) = sa_key_with_options(&sksa, &virage2, sk_check, &options)?;
+
+    if let Some(bootrom) = &bootrom {
+        let (sk_key, sk_iv) = bootrom_keys(bootrom)?;
+        let (sk_key, sk_iv) = (Zeroizing::new(sk_key), Zeroizing::new(sk_iv));
+
+        println!("SK key:        {}", sk_key.to_hex());
+        println!("SK IV:         {}", sk_iv.to_hex());
+    }
+
+    let common_cmd_iv = options.common_cmd_iv.unwrap_or(cmd.common_cmd_iv);
+    println!("common cmd IV: {}", common_cmd_iv.to_hex());
+    println!("SA1 key:       {}", sa1_key.to_hex());
+    println!("SA1 IV:        {}", cmd.iv.to_hex());
+
+    Ok(())
+}
+
+/// Expands directories into the files in them, leaving out any with `out_ext`, as those are most
+/// likely the outputs of an earlier batch
+fn batch_payloads(paths: &[PathBuf], out_ext: &str) -> Result<Vec<PathBuf>> {
+    let is_output = |path: &Path| {
+        path.extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case(out_ext))
+    };
+
+    let mut rv = vec![];
+    for path in paths {
+        if path.is_dir() {
+            let mut entries = std::fs::read_dir(path)?
+                .map(|e| e.map(|e| e.path()))
+                .collect::<Result<Vec<_>, _>>()?;
+            entries.retain(|p| p.is_file() && !is_output(p));
+            entries.sort();
+            rv.extend(entries);
+        } else {
+            rv.push(path.clone());
+        }
+    }
+    Ok(rv)
+}
+
+pub fn batch(mut args: BatchArgs) -> Result<()> {
+    read_layout(&args.layout, &mut args.options)?;
+
+    let payloads = batch_payloads(&args.payloads, &args.out_ext)?;
+    let jobs: Vec<_> = payloads
+        .into_iter()
+        .map(|path| {
+            let mut name = path.file_stem().unwrap_or_default().to_owned();
+            name.push(format!(".{}", args.out_ext));
+            let outfile = match &args.outdir {
+                Some(dir) => dir.join(name),
+                None => path.with_file_name(name),
+            };
+            (IOType::File(path), IOType::File(outfile))
+        })
+        .collect();
+
+    // check every output before writing any, so a bad batch doesn't leave half of its outputs
+    let mut outputs = HashSet::new();
+    for (infile, outfile) in &jobs {
+        if let IOType::File(path) = outfile {
+            if !outputs.insert(path) {
+                return Err(BBBSError::DuplicateOutput(path.clone()).into());
+            }
+        }
+        if !args.force {
+            let inputs = [infile, &args.sksa].into_iter().chain(args.keys.inputs());
+            check_overwrite(outfile, inputs)?;
+        }
+    }
+
+    let sksa = args.sksa.read()?;
+    check_iv_reuse(&sksa, &args.options, args.strict)?;
+
+    let (virage2, bootrom) = read_keys(&args.keys)?;
+    check_expected_sk(&args.keys, &sksa, args.options.sk_size, &bootrom)?;
+    let sk_check = sk_check(&args.keys, &bootrom)?;
+
+    let sk_size = args.options.sk_size;
+    let sa_offset = sa_offset_sized(&sksa, sk_size, args.options.sa_index)?;
+    let (cmd, sa1_key) = sa_key(&sksa, sa_offset, &virage2, sk_check, &args.options)?;
+    let sa1_iv = args.options.sa1_iv_from.iv(&cmd);
+
+    for (infile, outfile) in jobs {
+        // an ELF payload sets its own entrypoint, so each one starts from the shared options
+        let mut options = args.options.clone();
+        let payload = convert_payload(&args.layout, infile.read()?, &mut options)?;
+        let report = pack_sa(
+            payload,
+            &sksa,
+            &cmd,
+            &sa1_key,
+            &sa1_iv,
+            &options,
+            memory_output,
+        )?;
+        write_output(&outfile, &report.output)?;
+    }
+
+    Ok(())
+}
+
+pub fn mkvirage2(args: MkVirage2Args) -> Result<()> {
+    let mut fields = args.fields;
+    if let Some(json) = &args.json {
+        fields = fields.or(serde_json::from_slice(&json.read()?)?);
+    }
+
+    let virage2 = Virage2 {
+        sk_hash: fields.sk_hash.ok_or(BBBSError::MissingInput("SK hash"))?,
+        rom_patch: fields.rom_patch.unwrap_or_default(),
+        pub_key: fields.pub_key.unwrap_or([0; 64]),
+        bbid: fields.bbid.unwrap_or_default(),
+        priv_key: fields.priv_key.unwrap_or_default(),
+        boot_app_key: fields
+            .boot_app_key
+            .ok_or(BBBSError::MissingInput("boot app key"))?,
+        recrypt_list_key: fields.recrypt_list_key.unwrap_or_default(),
+        app_state_key: fields.app_state_key.unwrap_or_default(),
+        self_msg_key: fields.self_msg_key.unwrap_or_default(),
+        csum_adjust: 0,
+        jtag_enable: fields.jtag_enable.unwrap_or_default(),
+    };
+
+    let buf = virage2.to_buf();
+    read_virage2(&buf)?;
+
+    write_output(&args.outfile, buf)?;
+
+    Ok(())
+}
+
+pub fn bundle(args: BundleArgs) -> Result<()> {
+    let virage2 = args.virage2.read()?;
+    read_virage2(&virage2)?;
+
+    let bundle = Bundle {
+        sksa: Some(args.sksa.read()?),
+        virage2: Some(virage2),
+        bootrom: args.bootrom.as_ref().map(IOType::read).transpose()?,
+    };
+
+    write_output(&args.outfile, bundle.to_buf())?;
+
+    Ok(())
+}
+
+pub fn run(args: Args) -> Result<()> {
+    set_verbose(args.verbose);
+    set_quiet(args.quiet);
+
+    match args.command {
+        Command::Build(args) => {
+            let json = args.json.clone();
+            set_stats(args.stats);
+            build(*args)
+                .inspect(|_| {
+                    if STATS.load(Ordering::Relaxed) {
+                        print_stats();
+                    }
+                })
+                .inspect_err(|e| {
+                    if let Some(json) = json {
+                        let error = json!({
+                            "error": e.downcast_ref::<BBBSError>().map_or("Other", BBBSError::name),
+                            "message": e.to_string(),
+                        });
+                        let _ = json.write(format!("{error}\n"));
+                    }
+                })
+        }
+        Command::Extract(args) => extract(args),
+        Command::Verify(args) => verify(args),
+        Command::Validate(args) => validate(args),
+        Command::Info(args) => info(args),
+        Command::List(args) => list(args),
+        Command::Keys(args) => keys(args),
+        Command::Recrypt(args) => recrypt(args),
+        Command::Patch(args) => patch(args),
+        Command::Batch(args) => batch(args),
+        Command::MkVirage2(args) => mkvirage2(args),
+        Command::Bundle(args) => bundle(args),
+    }
+}
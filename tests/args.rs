@@ -0,0 +1,473 @@
+mod common;
+
+use bbbs::args::{parse_args_from, replace_extension_or, Command, IOType};
+use bbbs::{decrypt_sa1, BBBSError, BuildOptions, SkCheck, EXIT_CODES};
+use common::{bytes, elf, sksa, Fixtures, SK_SIZE};
+
+use std::ffi::OsString;
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+
+fn sksa_name(path: &str) -> PathBuf {
+    replace_extension_or(Path::new(path), &["bin"], "sksa")
+}
+
+#[test]
+fn replaces_bin_extension() {
+    assert_eq!(sksa_name("payload.bin"), PathBuf::from("payload.sksa"));
+}
+
+#[test]
+fn adds_missing_extension() {
+    assert_eq!(sksa_name("payload"), PathBuf::from("payload.sksa"));
+}
+
+#[test]
+fn appends_to_other_extensions() {
+    assert_eq!(sksa_name("payload.elf"), PathBuf::from("payload.elf.sksa"));
+}
+
+#[test]
+fn replaces_uppercase_extension() {
+    assert_eq!(sksa_name("payload.BIN"), PathBuf::from("payload.sksa"));
+}
+
+#[test]
+fn replaces_only_the_last_extension() {
+    assert_eq!(
+        sksa_name("my.payload.bin"),
+        PathBuf::from("my.payload.sksa")
+    );
+    assert_eq!(
+        sksa_name("payload.tar.bin"),
+        PathBuf::from("payload.tar.sksa")
+    );
+    assert_eq!(
+        sksa_name("payload.bin.tar"),
+        PathBuf::from("payload.bin.tar.sksa")
+    );
+}
+
+#[test]
+fn handles_names_without_a_stem() {
+    assert_eq!(sksa_name(".bin"), PathBuf::from(".bin.sksa"));
+    assert_eq!(sksa_name("dir/.bin"), PathBuf::from("dir/.bin.sksa"));
+    assert_eq!(sksa_name(""), PathBuf::from(".sksa"));
+    assert_eq!(sksa_name(".."), PathBuf::from("...sksa"));
+}
+
+#[test]
+fn derives_output_from_file() {
+    let infile = IOType::input("dir/payload.bin");
+    assert_eq!(
+        infile.derive_output(|p| replace_extension_or(p, &["bin"], "sksa")),
+        IOType::File(PathBuf::from("dir/payload.sksa"))
+    );
+}
+
+#[test]
+fn derives_stdout_from_stdin() {
+    let infile = IOType::input("-");
+    assert_eq!(infile, IOType::Stdin);
+    assert_eq!(infile.derive_output(|_| unreachable!()), IOType::Stdout);
+}
+
+#[test]
+fn derives_stdout_from_other_streams() {
+    for io in [IOType::Stdout, IOType::Stderr] {
+        assert_eq!(io.derive_output(|_| unreachable!()), IOType::Stdout);
+    }
+}
+
+#[cfg(feature = "net")]
+#[test]
+fn recognizes_url_inputs() {
+    for url in [
+        "http://example.com/sksa.bin",
+        "https://example.com/sksa.bin",
+    ] {
+        let infile = IOType::input(url);
+        assert_eq!(infile, IOType::Url(url.to_owned()));
+        assert_eq!(infile.to_string(), url);
+        assert_eq!(infile.derive_output(|_| unreachable!()), IOType::Stdout);
+    }
+}
+
+#[test]
+fn streams_files_through_reader_and_writer() {
+    let path = std::env::temp_dir().join(format!("bbbs-stream-{}", std::process::id()));
+    let file = IOType::File(path.clone());
+
+    let mut writer = file.writer().unwrap();
+    writer.write_all(b"streamed").unwrap();
+    drop(writer);
+
+    let mut rv = String::new();
+    file.reader().unwrap().read_to_string(&mut rv).unwrap();
+    assert_eq!(rv, "streamed");
+
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn rejects_streams_in_the_wrong_direction() {
+    let kind = |r: Result<(), std::io::Error>| r.unwrap_err().kind();
+    assert_eq!(
+        kind(IOType::Stdin.writer().map(drop)),
+        ErrorKind::Unsupported
+    );
+    assert_eq!(
+        kind(IOType::Stdout.reader().map(drop)),
+        ErrorKind::Unsupported
+    );
+    assert_eq!(
+        kind(IOType::Stderr.reader().map(drop)),
+        ErrorKind::Unsupported
+    );
+}
+
+#[test]
+fn positional_sksa_overrides_the_config() {
+    let dir = std::env::temp_dir().join(format!("bbbs-args-config-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("bbbs.toml");
+    std::fs::write(&config, "sksa = \"config.sksa\"\n").unwrap();
+
+    let info = |extra: &[&str]| {
+        let mut args = vec![
+            "bbbs".into(),
+            "--config".into(),
+            config.clone().into(),
+            "info".into(),
+        ];
+        args.extend(extra.iter().map(OsString::from));
+        match parse_args_from(args).command {
+            Command::Info(info) => info.sksa,
+            command => panic!("parsed {command:?} instead of info"),
+        }
+    };
+    assert_eq!(info(&["in.sksa"]), IOType::input("in.sksa"));
+    assert_eq!(info(&[]), IOType::input("config.sksa"));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn keys_and_recrypt_take_a_common_cmd_iv() {
+    let parse = |args: &[&str]| {
+        let mut argv = vec![OsString::from("bbbs")];
+        argv.extend(args.iter().map(OsString::from));
+        parse_args_from(argv).command
+    };
+    let iv = "5a".repeat(16);
+
+    match parse(&[
+        "keys",
+        "-s",
+        "in.sksa",
+        "-v",
+        "v2.bin",
+        "--no-verify",
+        "--dump-keys",
+        "--common-cmd-iv",
+        &iv,
+    ]) {
+        Command::Keys(keys) => assert_eq!(keys.common_cmd_iv, Some([0x5A; 16])),
+        command => panic!("parsed {command:?} instead of keys"),
+    }
+    match parse(&[
+        "recrypt",
+        "in.sksa",
+        "-v",
+        "v2.bin",
+        "--no-verify",
+        "--target-virage2",
+        "t.bin",
+        "--target-no-verify",
+        "--common-cmd-iv",
+        &iv,
+    ]) {
+        Command::Recrypt(recrypt) => assert_eq!(recrypt.common_cmd_iv, Some([0x5A; 16])),
+        command => panic!("parsed {command:?} instead of recrypt"),
+    }
+}
+
+#[test]
+fn custom_sk_size_works_for_every_subcommand() {
+    let fx = Fixtures::new("sk-size-cli");
+    let payload = bytes(35, 1000);
+    fs::write(fx.path("small.sksa"), &sksa()[SK_SIZE - 0x8000..]).unwrap();
+    fs::write(fx.path("payload.bin"), &payload).unwrap();
+
+    let (sksa, virage2, payload_path, built, extracted) = (
+        fx.path("small.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+        fx.path("out.sksa"),
+        fx.path("out.bin"),
+    );
+    let keys = ["-v", &virage2, "--no-verify", "--sk-size", "32768"];
+    fx.run(&[&["build", "-s", &sksa][..], &keys, &[&payload_path, &built]].concat());
+    fx.run(&[&["extract"][..], &keys, &[&built, &extracted]].concat());
+    fx.run(&[&["validate", "-s", &built][..], &keys, &[&payload_path]].concat());
+    fx.run(&["info", "--sk-size", "32768", &built]);
+    fx.run(&["list", "--sk-size", "32768", &built]);
+
+    assert_eq!(fs::read(&extracted).unwrap()[..payload.len()], payload);
+}
+
+#[test]
+fn verbose_build_logs_each_stage() {
+    let fx = Fixtures::new("verbose");
+    fs::write(fx.path("payload.bin"), bytes(18, 100)).unwrap();
+
+    let stderr = fx.stderr(&[
+        "--verbose",
+        "build",
+        "-s",
+        &fx.path("in.sksa"),
+        "-v",
+        &fx.path("virage2.bin"),
+        "--no-verify",
+        &fx.path("payload.bin"),
+        &fx.path("out.sksa"),
+    ]);
+    for stage in [
+        "read SKSA",
+        "derived SA1 key",
+        "encrypted SA1",
+        "wrote output",
+    ] {
+        assert!(stderr.contains(stage), "no {stage:?} in {stderr}");
+    }
+}
+
+#[test]
+fn errors_have_distinct_exit_codes() {
+    let fx = Fixtures::new("exit-code");
+    fs::write(fx.path("short.sksa"), bytes(20, 100)).unwrap();
+
+    let expected = BBBSError::SKSATooShort(0, 0).exit_code();
+    assert_eq!(
+        fx.code(&["list", &fx.path("short.sksa")]),
+        Some(expected.into())
+    );
+
+    let parse = || binrw::Error::AssertFail {
+        pos: 0,
+        message: String::new(),
+    };
+    let io = || std::io::Error::other("");
+    let errors = [
+        BBBSError::SKSATooShort(0, 0),
+        BBBSError::BootromTooShort(0),
+        BBBSError::PayloadTooLong(0, 0),
+        BBBSError::CompressedPayloadTooLong(0, 0, 0),
+        BBBSError::InvalidSKHash(String::new(), String::new()),
+        BBBSError::SATooShort(0, 0),
+        BBBSError::CmdHeadParse(parse()),
+        BBBSError::Virage2Parse(parse()),
+        BBBSError::Bootrom(bb::BootromError::InvalidHash(String::new())),
+        BBBSError::Decryption("", String::new()),
+        BBBSError::Encryption("", String::new()),
+        BBBSError::RegionOutOfBounds(0, 0, "", 0),
+        BBBSError::RoundtripMismatch,
+        BBBSError::InvalidVirage2(""),
+        BBBSError::CmdSizeTooSmall(0, 0),
+        BBBSError::PrebuiltPayload(""),
+        BBBSError::InvalidSA1Hash(String::new(), String::new()),
+        BBBSError::SKMismatch(0),
+        BBBSError::EmptyPayload,
+        BBBSError::MapOutput(io()),
+        BBBSError::MissingInput(""),
+        BBBSError::WouldOverwriteInput(PathBuf::new()),
+        #[cfg(feature = "sign")]
+        BBBSError::InvalidSignKey(String::new()),
+        #[cfg(feature = "sign")]
+        BBBSError::Signing(String::new()),
+        BBBSError::EntrypointOutOfBounds(0),
+        BBBSError::Io(io()),
+        BBBSError::InvalidSKSize(0),
+        BBBSError::SKPartSize(0, 0),
+        BBBSError::MissingSA1Region(0),
+        BBBSError::InvalidBundle(""),
+        BBBSError::PayloadHashMismatch(String::new(), String::new()),
+        BBBSError::SA1NotBlockAligned(0),
+        BBBSError::PayloadMismatch(0),
+        BBBSError::IvReuse(0, 0),
+        BBBSError::InvalidElf(String::new()),
+        BBBSError::WriteVerifyFailed(PathBuf::new(), 0),
+        BBBSError::PayloadOverlap(0, 0),
+        BBBSError::ExtraPayloadTooLong(0, 0),
+        BBBSError::PatchOutOfBounds(0, 0, 0),
+        BBBSError::DuplicateOutput(PathBuf::new()),
+        #[cfg(feature = "sign")]
+        BBBSError::SignedPreservedTail,
+    ];
+
+    // exhaustive, so a new error doesn't compile until it's added to `errors` above too
+    for error in &errors {
+        match error {
+            BBBSError::SKSATooShort(..)
+            | BBBSError::BootromTooShort(..)
+            | BBBSError::PayloadTooLong(..)
+            | BBBSError::CompressedPayloadTooLong(..)
+            | BBBSError::InvalidSKHash(..)
+            | BBBSError::SATooShort(..)
+            | BBBSError::CmdHeadParse(..)
+            | BBBSError::Virage2Parse(..)
+            | BBBSError::Bootrom(..)
+            | BBBSError::Decryption(..)
+            | BBBSError::Encryption(..)
+            | BBBSError::RegionOutOfBounds(..)
+            | BBBSError::RoundtripMismatch
+            | BBBSError::InvalidVirage2(..)
+            | BBBSError::CmdSizeTooSmall(..)
+            | BBBSError::PrebuiltPayload(..)
+            | BBBSError::InvalidSA1Hash(..)
+            | BBBSError::SKMismatch(..)
+            | BBBSError::EmptyPayload
+            | BBBSError::MapOutput(..)
+            | BBBSError::MissingInput(..)
+            | BBBSError::WouldOverwriteInput(..)
+            | BBBSError::EntrypointOutOfBounds(..)
+            | BBBSError::Io(..)
+            | BBBSError::InvalidSKSize(..)
+            | BBBSError::SKPartSize(..)
+            | BBBSError::MissingSA1Region(..)
+            | BBBSError::InvalidBundle(..)
+            | BBBSError::PayloadHashMismatch(..)
+            | BBBSError::SA1NotBlockAligned(..)
+            | BBBSError::PayloadMismatch(..)
+            | BBBSError::IvReuse(..)
+            | BBBSError::InvalidElf(..)
+            | BBBSError::WriteVerifyFailed(..)
+            | BBBSError::PayloadOverlap(..)
+            | BBBSError::ExtraPayloadTooLong(..)
+            | BBBSError::PatchOutOfBounds(..)
+            | BBBSError::DuplicateOutput(..) => {}
+            #[cfg(feature = "sign")]
+            BBBSError::InvalidSignKey(..)
+            | BBBSError::Signing(..)
+            | BBBSError::SignedPreservedTail => {}
+        }
+    }
+
+    let mut names: Vec<_> = errors.iter().map(BBBSError::name).collect();
+    names.sort();
+    names.dedup();
+    assert_eq!(names.len(), errors.len(), "two errors share a name");
+    assert_eq!(EXIT_CODES.len(), errors.len());
+    for error in &errors {
+        assert!(
+            EXIT_CODES.contains(&(error.name(), error.exit_code())),
+            "{} is missing from the exit code table",
+            error.name()
+        );
+    }
+
+    let mut codes: Vec<_> = EXIT_CODES.iter().map(|(_, code)| *code).collect();
+    codes.sort();
+    codes.dedup();
+    assert_eq!(
+        codes.len(),
+        EXIT_CODES.len(),
+        "two errors share an exit code"
+    );
+    assert!(!codes.contains(&1) && !codes.contains(&2));
+    assert!(!codes.contains(&19), "19 is reserved");
+}
+
+#[test]
+fn command_line_overrides_the_config() {
+    let fx = Fixtures::new("config");
+    let text = bytes(37, 0x200);
+    fs::write(
+        fx.path("payload.elf"),
+        elf(0x80301234, &[(0x80300000, &text)]),
+    )
+    .unwrap();
+    fs::write(
+        fx.path("bbbs.toml"),
+        format!(
+            "sksa = {:?}\nvirage2 = {:?}\nentrypoint = 0x80400000\ncompress = true\n",
+            fx.path("in.sksa"),
+            fx.path("virage2.bin"),
+        ),
+    )
+    .unwrap();
+
+    let (config, virage2, payload, built) = (
+        fx.path("bbbs.toml"),
+        fx.path("virage2.bin"),
+        fx.path("payload.elf"),
+        fx.path("out.sksa"),
+    );
+    let build = |extra: &[&str]| {
+        let args = ["--config", &config, "build", "--no-verify"];
+        fx.run(&[&args[..], extra, &[&payload, &built]].concat());
+        decrypt_sa1(
+            &fs::read(&built).unwrap(),
+            &fs::read(&virage2).unwrap(),
+            SkCheck::Skip,
+        )
+        .unwrap()
+    };
+
+    let start = BuildOptions::default().header_size;
+    let sa1 = build(&[]);
+    assert_eq!(sa1[8..12], 0x80400000u32.to_be_bytes());
+    assert_eq!(sa1[start..start + 2], [0x1F, 0x8B]);
+
+    let sa1 = build(&["--no-compress", "--entrypoint", "80300000"]);
+    assert_eq!(sa1[8..12], 0x80300000u32.to_be_bytes());
+    assert_eq!(sa1[start..start + 4], *b"\x7FELF");
+
+    // an entrypoint from the config doesn't stop the ELF's own being used
+    let sa1 = build(&["--input-format", "elf", "--no-compress"]);
+    assert_eq!(sa1[8..12], 0x80301234u32.to_be_bytes());
+    assert_eq!(sa1[start..start + text.len()], text);
+}
+
+#[test]
+fn info_needs_an_sksa_instead_of_reading_stdin() {
+    let fx = Fixtures::new("info-args");
+    let bbbs = |args: &[&str]| {
+        // stdin is left open, so reading it would hang rather than fail
+        let mut child = process::Command::new(env!("CARGO_BIN_EXE_bbbs"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        child.wait().unwrap().code()
+    };
+
+    let sksa = fx.path("in.sksa");
+    assert_eq!(bbbs(&["info"]), Some(2));
+    assert_eq!(bbbs(&["info", &sksa]), Some(0));
+    assert_eq!(bbbs(&["info", "--sksa", &sksa]), Some(0));
+    assert_eq!(bbbs(&["list", "-s", &sksa, &sksa]), Some(2));
+}
+
+#[test]
+fn derived_output_uses_out_ext() {
+    let fx = Fixtures::new("out-ext");
+    fs::write(fx.path("payload.bin"), bytes(28, 100)).unwrap();
+
+    let (sksa, virage2, payload) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+    );
+    let build = ["build", "-s", &sksa, "-v", &virage2, "--no-verify"];
+    fx.run(&[&build[..], &["--out-ext", "img", &payload]].concat());
+    fx.run(&[&build[..], &["--out-ext", ".rom", &payload]].concat());
+
+    assert!(fx.dir.join("payload.img").exists());
+    assert!(fx.dir.join("payload.rom").exists());
+    assert!(!fx.dir.join("payload.sksa").exists());
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use bbbs::{decrypt_sa1, BBBSError, BuildOptions, SkCheck};
+use common::{bytes, elf, Fixtures};
+
+use std::fs;
+use std::path::PathBuf;
+
+#[test]
+fn batch_can_be_rerun_and_rejects_colliding_outputs() {
+    let fx = Fixtures::new("batch");
+    let payloads = fx.dir.join("payloads");
+    fs::create_dir_all(&payloads).unwrap();
+    fs::write(payloads.join("a.bin"), bytes(40, 3000)).unwrap();
+    fs::write(payloads.join("b.bin"), bytes(41, 2000)).unwrap();
+
+    let (sksa, virage2) = (fx.path("in.sksa"), fx.path("virage2.bin"));
+    let dir = payloads.to_str().unwrap();
+    let batch = ["batch", "-s", &sksa, "-v", &virage2, "--no-verify", dir];
+
+    // the outputs from the first run sit next to the payloads, and aren't payloads themselves
+    fx.run(&batch);
+    fx.run(&batch);
+    for (name, payload) in [("a", bytes(40, 3000)), ("b", bytes(41, 2000))] {
+        let output = fs::read(payloads.join(format!("{name}.sksa"))).unwrap();
+        let sa1 = decrypt_sa1(&output, &fs::read(&virage2).unwrap(), SkCheck::Skip).unwrap();
+        let start = BuildOptions::default().header_size;
+        assert_eq!(sa1[start..start + payload.len()], payload);
+    }
+
+    fs::write(payloads.join("c.bin"), bytes(42, 1000)).unwrap();
+    fs::write(payloads.join("c.elf"), bytes(43, 1000)).unwrap();
+    let code = fx.code(&batch);
+    assert_eq!(
+        code,
+        Some(
+            BBBSError::DuplicateOutput(PathBuf::new())
+                .exit_code()
+                .into()
+        )
+    );
+    assert!(!payloads.join("c.sksa").exists());
+}
+
+#[test]
+fn batch_applies_the_layout_options() {
+    let fx = Fixtures::new("batch-layout");
+    let text = bytes(44, 0x200);
+    fs::write(
+        fx.path("payload.elf"),
+        elf(0x80301234, &[(0x80301000, &text)]),
+    )
+    .unwrap();
+    fs::write(fx.path("overlay.bin"), bytes(45, 0x100)).unwrap();
+
+    let (sksa, virage2) = (fx.path("in.sksa"), fx.path("virage2.bin"));
+    let extra = format!("10000:{}", fx.path("overlay.bin"));
+    fx.run(&[
+        "batch",
+        "-s",
+        &sksa,
+        "-v",
+        &virage2,
+        "--no-verify",
+        "--input-format",
+        "elf",
+        "--extra-payload",
+        &extra,
+        &fx.path("payload.elf"),
+    ]);
+
+    let output = fs::read(fx.path("payload.sksa")).unwrap();
+    let sa1 = decrypt_sa1(&output, &fs::read(&virage2).unwrap(), SkCheck::Skip).unwrap();
+    let start = BuildOptions::default().header_size;
+    assert_eq!(sa1[8..12], 0x80301234u32.to_be_bytes());
+    assert_eq!(sa1[start..start + text.len()], text);
+    assert_eq!(sa1[0x10000..0x10100], bytes(45, 0x100));
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use bbbs::{decrypt_sa1, BBBSError, Bundle, SkCheck, SksaBuilder};
+use common::{bytes, sksa, virage2, Fixtures, SK_HASH, SK_SIZE};
+
+use std::fs;
+
+#[test]
+fn mkvirage2_output_is_usable() {
+    let fx = Fixtures::new("mkvirage2");
+    let made = fx.path("made.bin");
+    fx.run(&[
+        "mkvirage2",
+        "--sk-hash",
+        &"5A".repeat(20),
+        "--boot-app-key",
+        "00112233445566778899AABBCCDDEEFF",
+        &made,
+    ]);
+
+    let virage2 = fs::read(&made).unwrap();
+    let output = SksaBuilder::new()
+        .payload(bytes(9, 100))
+        .sksa(sksa())
+        .virage2(virage2.clone())
+        .sk_hash(SK_HASH)
+        .build()
+        .unwrap();
+
+    assert!(decrypt_sa1(&output, &virage2, SkCheck::Hash(SK_HASH)).is_ok());
+}
+
+#[test]
+fn separate_sk_and_cmd_match_combined_sksa() {
+    let fx = Fixtures::new("sk-cmd");
+    let sksa = sksa();
+    fs::write(fx.path("sk.bin"), &sksa[..SK_SIZE]).unwrap();
+    fs::write(fx.path("cmd.bin"), &sksa[SK_SIZE..]).unwrap();
+    fs::write(fx.path("payload.bin"), bytes(19, 100)).unwrap();
+
+    let (virage2, payload) = (fx.path("virage2.bin"), fx.path("payload.bin"));
+    let (combined, parts) = (fx.path("combined.sksa"), fx.path("parts.sksa"));
+    let build = |source: &[&str], out: &str| {
+        let mut args = vec!["build", "-v", &virage2, "--no-verify"];
+        args.extend(source);
+        args.extend([payload.as_str(), out]);
+        fx.run(&args);
+    };
+    build(&["-s", &fx.path("in.sksa")], &combined);
+    build(
+        &["--sk", &fx.path("sk.bin"), "--cmd", &fx.path("cmd.bin")],
+        &parts,
+    );
+
+    assert_eq!(fs::read(&combined).unwrap(), fs::read(&parts).unwrap());
+}
+
+#[test]
+fn bundle_roundtrips() {
+    let bundle = Bundle {
+        sksa: Some(sksa()),
+        virage2: Some(virage2()),
+        bootrom: None,
+    };
+
+    assert_eq!(Bundle::read_from_buf(&bundle.to_buf()).unwrap(), bundle);
+    assert!(matches!(
+        Bundle::read_from_buf(&sksa()),
+        Err(BBBSError::InvalidBundle(_))
+    ));
+}
+
+#[test]
+fn build_from_bundle_matches_separate_inputs() {
+    let fx = Fixtures::new("bundle");
+    fs::write(fx.path("payload.bin"), bytes(21, 100)).unwrap();
+
+    let (sksa, virage2, payload) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+    );
+    let (bundle, separate, bundled) = (
+        fx.path("console.bundle"),
+        fx.path("separate.sksa"),
+        fx.path("bundled.sksa"),
+    );
+    fx.run(&["bundle", "-s", &sksa, "-v", &virage2, &bundle]);
+
+    let build = |inputs: &[&str], out: &str| {
+        let mut args = vec!["build", "--no-verify"];
+        args.extend(inputs);
+        args.extend([payload.as_str(), out]);
+        fx.run(&args);
+    };
+    build(&["-s", &sksa, "-v", &virage2], &separate);
+    build(&["--bundle", &bundle], &bundled);
+
+    assert_eq!(fs::read(&separate).unwrap(), fs::read(&bundled).unwrap());
+}
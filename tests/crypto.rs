@@ -0,0 +1,597 @@
+mod common;
+
+use bbbs::crypto::{
+    cbc_decrypt_in_place, cbc_encrypt_in_place, ctr_apply_in_place, DefaultSha1, Sha1Backend,
+};
+use bbbs::{
+    build_sksa_with_options, check_roundtrip, check_sa_hash, decrypt_sa1, decrypt_sa1_with_mode,
+    decrypt_sa_with_options, output_size, patch_sksa, read_cmd_head_at, recrypt_sksa_with_options,
+    reused_iv_sas, sa_key_with_options, sa_offset, validate_sksa, verify_sk, BBBSError,
+    BuildOptions, CipherMode, IvSource, SkCheck, SksaBuilder,
+};
+#[cfg(feature = "sign")]
+use common::SIGN_KEY;
+use common::{
+    bytes, sksa, sksa_with_sa2, virage2, virage2_seeded, Fixtures, SA1_INFO_BLOCK_SIZE, SA1_SIZE,
+    SK_HASH, SK_SIZE,
+};
+use zeroize::{Zeroize, Zeroizing};
+
+use std::fs;
+use std::sync::Arc;
+
+#[test]
+fn zero_iv_sksa_validates_and_patches() {
+    let payload = bytes(36, 500);
+    let options = BuildOptions {
+        sa1_iv_from: IvSource::Zero,
+        ..Default::default()
+    };
+    let output = build_sksa_with_options(
+        payload.clone(),
+        &sksa(),
+        &virage2(),
+        SkCheck::Skip,
+        &options,
+    )
+    .unwrap()
+    .output;
+
+    validate_sksa(
+        &output,
+        payload.clone(),
+        &virage2(),
+        SkCheck::Skip,
+        &options,
+    )
+    .unwrap();
+
+    let patch = [(8, 0x80301000u32.to_be_bytes().to_vec())];
+    let patched = patch_sksa(&output, &virage2(), SkCheck::Skip, &patch, &options).unwrap();
+    let sa1 = decrypt_sa_with_options(&patched, &virage2(), SkCheck::Skip, &options).unwrap();
+    assert_eq!(sa1[..8], [0; 8]);
+    assert_eq!(sa1[8..12], 0x80301000u32.to_be_bytes());
+    validate_sksa(&patched, payload, &virage2(), SkCheck::Skip, &options).unwrap();
+}
+
+#[test]
+fn recrypt_keeps_the_cipher_mode() {
+    let target = virage2_seeded(8);
+    for cipher_mode in [CipherMode::Cbc, CipherMode::Ctr] {
+        let options = BuildOptions {
+            cipher_mode,
+            ..Default::default()
+        };
+        let report =
+            build_sksa_with_options(bytes(37, 500), &sksa(), &virage2(), SkCheck::Skip, &options)
+                .unwrap();
+
+        let recrypted = recrypt_sksa_with_options(
+            &report.output,
+            &virage2(),
+            SkCheck::Skip,
+            &target,
+            SkCheck::Skip,
+            &options,
+        )
+        .unwrap();
+        assert_ne!(recrypted, report.output);
+        let sa1 = decrypt_sa_with_options(&recrypted, &target, SkCheck::Skip, &options).unwrap();
+        assert_eq!(sa1, report.plain_sa1);
+    }
+}
+
+#[test]
+fn recrypt_uses_the_common_cmd_iv_override() {
+    let options = BuildOptions {
+        common_cmd_iv: Some([0x5A; 16]),
+        ..Default::default()
+    };
+    let report =
+        build_sksa_with_options(bytes(37, 500), &sksa(), &virage2(), SkCheck::Skip, &options)
+            .unwrap();
+    let target = virage2_seeded(8);
+
+    let recrypt = |options: &BuildOptions| {
+        recrypt_sksa_with_options(
+            &report.output,
+            &virage2(),
+            SkCheck::Skip,
+            &target,
+            SkCheck::Skip,
+            options,
+        )
+        .unwrap()
+    };
+    let sa1 =
+        decrypt_sa_with_options(&recrypt(&options), &target, SkCheck::Skip, &options).unwrap();
+    assert_eq!(sa1, report.plain_sa1);
+
+    let recrypted = recrypt(&Default::default());
+    let sa1 = decrypt_sa_with_options(&recrypted, &target, SkCheck::Skip, &options).unwrap();
+    assert_ne!(sa1, report.plain_sa1);
+}
+
+#[test]
+fn recrypt_keeps_the_other_sas_and_the_tail() {
+    let sksa = sksa_with_sa2(0x100);
+
+    let options = BuildOptions {
+        sa_index: 2,
+        preserve_tail: true,
+        ..Default::default()
+    };
+    let report =
+        build_sksa_with_options(bytes(37, 500), &sksa, &virage2(), SkCheck::Skip, &options)
+            .unwrap();
+    let sa2_offset = sa_offset(&report.output, 2).unwrap();
+    let target = virage2_seeded(8);
+
+    let recrypt = |options: &BuildOptions| {
+        recrypt_sksa_with_options(
+            &report.output,
+            &virage2(),
+            SkCheck::Skip,
+            &target,
+            SkCheck::Skip,
+            options,
+        )
+        .unwrap()
+    };
+
+    let recrypted = recrypt(&options);
+    assert_eq!(recrypted.len(), report.output.len());
+    assert_eq!(recrypted[..sa2_offset], report.output[..sa2_offset]);
+    let end = sa2_offset + SA1_INFO_BLOCK_SIZE + report.plain_sa1.len();
+    assert_eq!(recrypted[end..], report.output[end..]);
+    let sa2 = decrypt_sa_with_options(&recrypted, &target, SkCheck::Skip, &options).unwrap();
+    assert_eq!(sa2, report.plain_sa1);
+
+    let recrypted = recrypt(&Default::default());
+    assert_eq!(recrypted.len(), report.output.len());
+    assert_ne!(recrypted[..sa2_offset], report.output[..sa2_offset]);
+    assert_eq!(recrypted[sa2_offset..], report.output[sa2_offset..]);
+}
+
+#[test]
+fn derived_keys_are_wiped() {
+    let (_, mut key): (_, Zeroizing<[u8; 16]>) =
+        sa_key_with_options(&sksa(), &virage2(), SkCheck::Skip, &Default::default()).unwrap();
+    assert_ne!(*key, [0; 16]);
+
+    key.zeroize();
+    assert_eq!(*key, [0; 16]);
+}
+
+#[test]
+fn corrupted_output_fails_the_roundtrip_check() {
+    let options = BuildOptions {
+        check_roundtrip: true,
+        ..Default::default()
+    };
+    let report = build_sksa_with_options(
+        bytes(31, 4000),
+        &sksa(),
+        &virage2(),
+        SkCheck::Skip,
+        &options,
+    )
+    .unwrap();
+
+    let (cmd, key) =
+        sa_key_with_options(&report.output, &virage2(), SkCheck::Skip, &options).unwrap();
+    let start = SK_SIZE + SA1_INFO_BLOCK_SIZE;
+    let mut sa1 = report.output[start..start + report.plain_sa1.len()].to_vec();
+    check_roundtrip(&report.plain_sa1, &sa1, &key, &cmd.iv, CipherMode::Cbc).unwrap();
+
+    sa1[0x1000] ^= 1;
+    let err = check_roundtrip(&report.plain_sa1, &sa1, &key, &cmd.iv, CipherMode::Cbc).unwrap_err();
+    assert!(matches!(err, BBBSError::RoundtripMismatch));
+    assert_eq!(err.exit_code(), 15);
+}
+
+#[test]
+fn library_roundtrip_checks_sk_hash() {
+    let sksa = sksa();
+    let virage2 = virage2();
+    let payload = bytes(6, 1234);
+
+    let output = SksaBuilder::new()
+        .payload(payload.clone())
+        .sksa(sksa.clone())
+        .virage2(virage2.clone())
+        .sk_hash(SK_HASH)
+        .build()
+        .unwrap();
+
+    assert_eq!(output.len(), sksa.len());
+    assert_eq!(
+        output.len(),
+        output_size(&sksa, &Default::default()).unwrap()
+    );
+    assert_eq!(
+        output[..SK_SIZE + SA1_INFO_BLOCK_SIZE],
+        sksa[..SK_SIZE + SA1_INFO_BLOCK_SIZE]
+    );
+
+    let sa1 = decrypt_sa1(&output, &virage2, SkCheck::Hash(SK_HASH)).unwrap();
+    let start = BuildOptions::default().header_size;
+    assert_eq!(sa1[start..start + payload.len()], payload);
+}
+
+#[test]
+fn wrong_sk_hash_is_rejected() {
+    let result = SksaBuilder::new()
+        .payload(bytes(7, 100))
+        .sksa(sksa())
+        .virage2(virage2())
+        .sk_hash([0xA5; 20])
+        .build();
+
+    assert!(result.is_err());
+}
+
+#[derive(Debug)]
+struct ConstantSha1;
+
+impl Sha1Backend for ConstantSha1 {
+    fn digest(&self, _data: &[u8]) -> [u8; 20] {
+        [0xEE; 20]
+    }
+}
+
+#[test]
+fn sha1_backend_is_used() {
+    let options = BuildOptions {
+        sha1_backend: Some(Arc::new(ConstantSha1)),
+        ..Default::default()
+    };
+    let report =
+        build_sksa_with_options(bytes(10, 100), &sksa(), &virage2(), SkCheck::Skip, &options)
+            .unwrap();
+
+    assert_eq!(report.sa1_hash, [0xEE; 20]);
+
+    // the SA1 hash check decrypts and hashes the way the build options say to
+    let zero_iv = BuildOptions {
+        sa1_iv_from: IvSource::Zero,
+        ..Default::default()
+    };
+    let built =
+        build_sksa_with_options(bytes(10, 100), &sksa(), &virage2(), SkCheck::Skip, &zero_iv)
+            .unwrap();
+    let check = |options: &BuildOptions, expected| {
+        check_sa_hash(&built.output, &virage2(), SkCheck::Skip, options, expected)
+    };
+    check(&zero_iv, &built.sa1_hash).unwrap();
+    check(
+        &BuildOptions {
+            sa1_iv_from: IvSource::Zero,
+            ..options
+        },
+        &[0xEE; 20],
+    )
+    .unwrap();
+    assert!(matches!(
+        check(&Default::default(), &built.sa1_hash),
+        Err(BBBSError::InvalidSA1Hash(..))
+    ));
+}
+
+#[cfg(feature = "sign")]
+#[test]
+fn signature_follows_sa1() {
+    use rsa::Pkcs1v15Sign;
+    use sha1::{Digest, Sha1};
+
+    let key = bbbs::parse_sign_key(SIGN_KEY).unwrap();
+    let public = key.to_public_key();
+
+    let sksa = sksa();
+    let builder = || {
+        SksaBuilder::new()
+            .payload(bytes(14, 100))
+            .sksa(sksa.clone())
+            .virage2(virage2())
+            .no_verify(true)
+            .sign_key(key.clone())
+    };
+    let output = builder().build().unwrap();
+
+    let (sa1, signature) = output[SK_SIZE + SA1_INFO_BLOCK_SIZE..].split_at(SA1_SIZE as usize);
+    assert_eq!(output.len(), sksa.len() + 128);
+    public
+        .verify(Pkcs1v15Sign::new::<Sha1>(), &Sha1::digest(sa1), signature)
+        .unwrap();
+
+    assert!(matches!(
+        builder().preserve_tail(true).build(),
+        Err(BBBSError::SignedPreservedTail)
+    ));
+}
+
+#[test]
+fn sk_hash_is_null_without_a_virage2() {
+    let fx = Fixtures::new("no-sk-hash");
+    fs::write(fx.path("payload.bin"), bytes(18, 3000)).unwrap();
+
+    let (sksa, payload, built) = (
+        fx.path("in.sksa"),
+        fx.path("payload.bin"),
+        fx.path("out.sksa"),
+    );
+    let (summary, manifest) = (fx.path("summary.json"), fx.path("manifest.json"));
+    let key = "00".repeat(16);
+    fx.run(&[
+        "build",
+        "-s",
+        &sksa,
+        "--no-verify",
+        "--sa1-key",
+        &key,
+        "--sa1-iv",
+        &key,
+        &format!("--json={summary}"),
+        "--manifest",
+        &manifest,
+        &payload,
+        &built,
+    ]);
+
+    let read = |path: &str| -> serde_json::Value {
+        serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+    };
+    assert!(read(&summary)["sk_hash"].is_null());
+    assert!(read(&manifest)["hashes"]["sk"].is_null());
+}
+
+#[test]
+fn expected_sk_needs_a_bootrom() {
+    let fx = Fixtures::new("expect-sk");
+    fs::write(fx.path("payload.bin"), bytes(21, 3000)).unwrap();
+    fs::write(fx.path("sk.bin"), bytes(22, SK_SIZE)).unwrap();
+
+    let code = fx.code(&[
+        "build",
+        "-s",
+        &fx.path("in.sksa"),
+        "-v",
+        &fx.path("virage2.bin"),
+        "--expect-sk",
+        &fx.path("sk.bin"),
+        &fx.path("payload.bin"),
+        &fx.path("out.sksa"),
+    ]);
+    assert_eq!(code, Some(BBBSError::MissingInput("").exit_code().into()));
+}
+
+#[test]
+fn ignored_sk_hash_mismatch_still_builds() {
+    let fx = Fixtures::new("ignore-sk-hash");
+    fs::write(fx.path("payload.bin"), bytes(18, 100)).unwrap();
+
+    let (sksa, virage2) = (fx.path("in.sksa"), fx.path("virage2.bin"));
+    let (payload, built) = (fx.path("payload.bin"), fx.path("out.sksa"));
+    let sk_hash = "A5".repeat(20);
+    let build = [
+        "build",
+        "-f",
+        "-s",
+        &sksa,
+        "-v",
+        &virage2,
+        "--sk-hash",
+        &sk_hash,
+        "--ignore-sk-hash",
+        &payload,
+        &built,
+    ];
+    let stderr = |extra: &[&str]| fx.stderr(&[&build[..], extra].concat());
+
+    assert!(stderr(&[]).contains("WARNING: SK hash mismatch"));
+    assert!(fs::metadata(&built).is_ok());
+    assert!(!stderr(&["--quiet"]).contains("SK hash mismatch"));
+}
+
+#[test]
+fn sk_hash_check_accepts_only_the_right_hash() {
+    let (sksa, virage2) = (sksa(), virage2());
+
+    assert!(verify_sk(&sksa, &virage2, SkCheck::Hash(SK_HASH)).is_ok());
+
+    let mut wrong = SK_HASH;
+    wrong[19] ^= 1;
+    assert!(matches!(
+        verify_sk(&sksa, &virage2, SkCheck::Hash(wrong)),
+        Err(BBBSError::InvalidSKHash(..))
+    ));
+}
+
+#[test]
+fn reused_ivs_are_found_across_sas() {
+    let mut sksa = sksa_with_sa2(0);
+
+    assert_eq!(
+        reused_iv_sas(&sksa, SK_SIZE, 1, IvSource::Header).unwrap(),
+        [2]
+    );
+    assert_eq!(
+        reused_iv_sas(&sksa, SK_SIZE, 2, IvSource::Header).unwrap(),
+        [1]
+    );
+    assert!(reused_iv_sas(&sksa, SK_SIZE, 2, IvSource::Zero)
+        .unwrap()
+        .is_empty());
+
+    // a different IV under the same key is fine
+    let iv = SK_SIZE + SA1_INFO_BLOCK_SIZE + SA1_SIZE as usize + 0x38;
+    sksa[iv] ^= 1;
+    assert!(reused_iv_sas(&sksa, SK_SIZE, 2, IvSource::Header)
+        .unwrap()
+        .is_empty());
+}
+
+#[test]
+fn build_checks_every_sa_for_a_reused_iv() {
+    let fx = Fixtures::new("iv-reuse");
+    fs::write(fx.path("in.sksa"), sksa_with_sa2(0)).unwrap();
+    fs::write(fx.path("payload.bin"), bytes(27, 3000)).unwrap();
+
+    let (sksa, virage2, payload, built) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+        fx.path("out.sksa"),
+    );
+    let build = ["build", "-s", &sksa, "-v", &virage2, "--no-verify"];
+
+    let strict = [&build[..], &["--strict", &payload, &built]].concat();
+    assert_eq!(fx.code(&strict), Some(37));
+
+    let stderr = |extra: &[&str]| fx.stderr(&[&build[..], extra, &[&payload, &built]].concat());
+    assert!(stderr(&[]).contains("WARNING: SA1 would be encrypted"));
+    assert!(stderr(&["--quiet"]).is_empty());
+}
+
+#[test]
+fn common_cmd_iv_override_recovers_a_damaged_info_block() {
+    let payload = bytes(26, 1000);
+    let build = |sksa: &[u8], options: &BuildOptions| {
+        build_sksa_with_options(payload.clone(), sksa, &virage2(), SkCheck::Skip, options)
+            .unwrap()
+            .output
+    };
+
+    let good = sksa();
+    let common_cmd_iv = SK_SIZE + 0x14..SK_SIZE + 0x24;
+    let mut damaged = good.clone();
+    damaged[common_cmd_iv.clone()].fill(0xFF);
+
+    let expected = build(&good, &Default::default());
+    let options = BuildOptions {
+        common_cmd_iv: Some(good[common_cmd_iv].try_into().unwrap()),
+        ..Default::default()
+    };
+    let sa1 = SK_SIZE + SA1_INFO_BLOCK_SIZE;
+    assert_eq!(build(&damaged, &options)[sa1..], expected[sa1..]);
+    assert_ne!(build(&damaged, &Default::default())[sa1..], expected[sa1..]);
+}
+
+#[test]
+fn ctr_matches_the_sp800_38a_vector() {
+    let key = 0x2b7e151628aed2a6abf7158809cf4f3cu128.to_be_bytes();
+    let counter = 0xf0f1f2f3f4f5f6f7f8f9fafbfcfdfeffu128.to_be_bytes();
+    let mut data = [
+        0x6bc1bee22e409f96e93d7e117393172au128.to_be_bytes(),
+        0xae2d8a571e03ac9c9eb76fac45af8e51u128.to_be_bytes(),
+    ]
+    .concat();
+
+    ctr_apply_in_place(&mut data, &key, &counter);
+    assert_eq!(
+        data,
+        [
+            0x874d6191b620e3261bef6864990db6ceu128.to_be_bytes(),
+            0x9806f66b7970fdff8617187bb9fffdffu128.to_be_bytes(),
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn cbc_matches_the_sp800_38a_vector() {
+    let key = 0x2b7e151628aed2a6abf7158809cf4f3cu128.to_be_bytes();
+    let iv = 0x000102030405060708090a0b0c0d0e0fu128.to_be_bytes();
+    let plain = [
+        0x6bc1bee22e409f96e93d7e117393172au128.to_be_bytes(),
+        0xae2d8a571e03ac9c9eb76fac45af8e51u128.to_be_bytes(),
+    ]
+    .concat();
+
+    let mut data = plain.clone();
+    cbc_encrypt_in_place(&mut data, &key, &iv).unwrap();
+    assert_eq!(
+        data,
+        [
+            0x7649abac8119b246cee98e9b12e9197du128.to_be_bytes(),
+            0x5086cb9b507219ee95db113a917678b2u128.to_be_bytes(),
+        ]
+        .concat()
+    );
+
+    cbc_decrypt_in_place(&mut data, &key, &iv).unwrap();
+    assert_eq!(data, plain);
+}
+
+#[test]
+fn cipher_modes_roundtrip_and_cbc_is_unchanged() {
+    let build = |mode, progress| {
+        SksaBuilder::new()
+            .payload(bytes(29, 0x18000))
+            .sksa(sksa())
+            .virage2(virage2())
+            .no_verify(true)
+            .cipher_mode(mode)
+            .progress(progress)
+            .build()
+            .unwrap()
+    };
+
+    let cbc = build(CipherMode::Cbc, false);
+    assert_eq!(
+        DefaultSha1.digest(&cbc),
+        // output from before CTR support was added
+        [
+            0x46, 0xEE, 0x2B, 0x45, 0xB1, 0xBB, 0x5F, 0x03, 0x7F, 0xC6, 0x93, 0x70, 0x02, 0xCA,
+            0x66, 0xB5, 0xD0, 0xF9, 0x7E, 0xE0
+        ]
+    );
+
+    let ctr = build(CipherMode::Ctr, false);
+    assert_eq!(build(CipherMode::Ctr, true), ctr);
+    assert_ne!(ctr, cbc);
+    assert_eq!(
+        decrypt_sa1_with_mode(&ctr, &virage2(), SkCheck::Skip, CipherMode::Ctr).unwrap(),
+        decrypt_sa1(&cbc, &virage2(), SkCheck::Skip).unwrap()
+    );
+}
+
+#[test]
+fn rebuilding_warns_about_a_stale_cmd_head_hash() {
+    let fx = Fixtures::new("stale-hash");
+    let payload = bytes(29, 3000);
+    let report = build_sksa_with_options(
+        payload.clone(),
+        &sksa(),
+        &virage2(),
+        SkCheck::Skip,
+        &Default::default(),
+    )
+    .unwrap();
+    assert_ne!(
+        read_cmd_head_at(&sksa(), SK_SIZE).unwrap().hash,
+        report.sa1_hash
+    );
+
+    // the CmdHead's hash is 0x24 bytes in
+    let mut matching = sksa();
+    matching[SK_SIZE + 0x24..SK_SIZE + 0x38].copy_from_slice(&report.sa1_hash);
+    fs::write(fx.path("matching.sksa"), matching).unwrap();
+    fs::write(fx.path("payload.bin"), payload).unwrap();
+
+    let (virage2, payload, built) = (
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+        fx.path("out.sksa"),
+    );
+    let keys = ["-f", "-v", &virage2, "--no-verify"];
+    let stderr = |args: &[&str]| fx.stderr(&[args, &keys].concat());
+    let warning = "WARNING: SA1 hash";
+
+    let build = |sksa: &str| stderr(&["build", "-s", sksa, &payload, &built]);
+    assert!(build(&fx.path("in.sksa")).contains(warning));
+    assert!(!build(&fx.path("matching.sksa")).contains(warning));
+
+    let patched = fx.path("patched.sksa");
+    assert!(stderr(&["patch", "--patch", "0x2000:DEADBEEF", &built, &patched]).contains(warning));
+}
@@ -0,0 +1,102 @@
+mod common;
+
+use bbbs::elf::flatten;
+use bbbs::{decrypt_sa1, BBBSError, BuildOptions, SkCheck};
+use common::{bytes, elf, Fixtures};
+
+use std::fs;
+
+#[test]
+fn elf_segments_are_flattened_in_address_order() {
+    let text = bytes(31, 0x100);
+    let data = bytes(32, 0x20);
+    let flat = flatten(&elf(
+        0x80300040,
+        &[(0x80300200, &data), (0x80300000, &text)],
+    ))
+    .unwrap();
+
+    assert_eq!(flat.base, 0x80300000);
+    assert_eq!(flat.entrypoint, 0x80300040);
+    assert_eq!(flat.data.len(), 0x220);
+    assert_eq!(flat.data[..0x100], text);
+    assert!(flat.data[0x100..0x200].iter().all(|&b| b == 0));
+    assert_eq!(flat.data[0x200..], data);
+
+    assert!(matches!(
+        flatten(b"not an elf"),
+        Err(BBBSError::InvalidElf(_))
+    ));
+}
+
+#[test]
+fn elf_segment_past_the_address_space_is_rejected() {
+    let mut rv = vec![0x7F, b'E', b'L', b'F', 2, 2, 1];
+    rv.resize(16, 0);
+    rv.extend(2u16.to_be_bytes());
+    rv.extend(8u16.to_be_bytes());
+    rv.extend(1u32.to_be_bytes());
+    for word in [0x80300000, 64, 0] {
+        rv.extend(u64::to_be_bytes(word));
+    }
+    rv.extend(0u32.to_be_bytes());
+    for half in [64, 56, 1, 64, 0, 0] {
+        rv.extend(u16::to_be_bytes(half));
+    }
+
+    let vaddr = u64::MAX - 0xF;
+    rv.extend(1u32.to_be_bytes());
+    rv.extend(5u32.to_be_bytes());
+    for word in [120, vaddr, vaddr, 0x20, 0x20, 4] {
+        rv.extend(u64::to_be_bytes(word));
+    }
+    rv.extend(bytes(38, 0x20));
+
+    assert!(matches!(
+        flatten(&rv),
+        Err(BBBSError::InvalidElf(e)) if e.contains("past the end of the address space")
+    ));
+}
+
+#[test]
+fn elf_input_uses_its_entrypoint_unless_overridden() {
+    let fx = Fixtures::new("elf");
+    let text = bytes(33, 0x400);
+    fs::write(
+        fx.path("payload.elf"),
+        elf(0x80301234, &[(0x80300000, &text)]),
+    )
+    .unwrap();
+
+    let (sksa, virage2, payload) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.elf"),
+    );
+    let build = |extra: &[&str]| {
+        let built = fx.path("out.sksa");
+        let args = ["build", "-s", &sksa, "-v", &virage2, "--no-verify"];
+        let args = [
+            &args[..],
+            &["--input-format", "elf"],
+            extra,
+            &[&payload, &built],
+        ]
+        .concat();
+        fx.run(&args);
+        decrypt_sa1(
+            &fs::read(&built).unwrap(),
+            &fs::read(&virage2).unwrap(),
+            SkCheck::Skip,
+        )
+        .unwrap()
+    };
+
+    let start = BuildOptions::default().header_size;
+    let sa1 = build(&[]);
+    assert_eq!(sa1[8..12], 0x80301234u32.to_be_bytes());
+    assert_eq!(sa1[start..start + text.len()], text);
+
+    let sa1 = build(&["--entrypoint", "80300000"]);
+    assert_eq!(sa1[8..12], 0x80300000u32.to_be_bytes());
+}
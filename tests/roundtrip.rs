@@ -0,0 +1,708 @@
+mod common;
+
+use bbbs::{
+    build_sksa_with_options, decrypt_sa1, list_sas, make_sa1, output_size, patch_sksa, trimmed_len,
+    validate_sksa, BBBSError, BuildOptions, BuildStage, Endian, SkCheck, SksaBuilder,
+};
+#[cfg(feature = "sign")]
+use common::SIGN_KEY;
+use common::{
+    bytes, sksa, sksa_with_sa2, virage2, Fixtures, SA1_INFO_BLOCK_SIZE, SA1_SIZE, SK_HASH, SK_SIZE,
+};
+
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+fn roundtrip(name: &str, extra: &[&str]) {
+    let fx = Fixtures::new(name);
+    let payload = bytes(5, 5000);
+    fs::write(fx.path("payload.bin"), &payload).unwrap();
+
+    let (sksa, virage2) = (fx.path("in.sksa"), fx.path("virage2.bin"));
+    let (payload_path, built, extracted) = (
+        fx.path("payload.bin"),
+        fx.path("out.sksa"),
+        fx.path("out.bin"),
+    );
+
+    let mut build = vec!["build", "-s", &sksa, "-v", &virage2, "--no-verify"];
+    build.extend(extra);
+    build.extend([payload_path.as_str(), built.as_str()]);
+    fx.run(&build);
+
+    let mut extract = vec!["extract", "-v", &virage2, "--no-verify"];
+    extract.extend(extra);
+    extract.extend([built.as_str(), extracted.as_str()]);
+    fx.run(&extract);
+
+    let recovered = fs::read(&extracted).unwrap();
+    assert_eq!(&recovered[..payload.len()], payload);
+    assert!(recovered[payload.len()..].iter().all(|&b| b == 0));
+}
+
+#[test]
+fn build_then_extract() {
+    roundtrip("plain", &[]);
+}
+
+#[test]
+fn build_then_extract_with_offsets() {
+    roundtrip(
+        "offsets",
+        &["--header-size", "2048", "--payload-offset", "100"],
+    );
+}
+
+#[test]
+fn build_then_extract_with_zero_iv() {
+    // a wrong IV only garbles the first block, so put the payload in it
+    roundtrip("zero-iv", &["--sa1-iv-from", "zero", "--header-size", "12"]);
+}
+
+#[test]
+fn progress_output_matches() {
+    let build = |progress| {
+        SksaBuilder::new()
+            .payload(bytes(8, 0x18000))
+            .sksa(sksa())
+            .virage2(virage2())
+            .no_verify(true)
+            .progress(progress)
+            .build()
+            .unwrap()
+    };
+
+    assert_eq!(build(true), build(false));
+}
+
+#[test]
+fn minimal_sksa_builds_but_has_no_sa1() {
+    let sksa = sksa()[..SK_SIZE + SA1_INFO_BLOCK_SIZE].to_vec();
+    let virage2 = virage2();
+
+    let result = decrypt_sa1(&sksa, &virage2, SkCheck::Skip);
+    assert!(matches!(result, Err(BBBSError::MissingSA1Region(len)) if len == sksa.len()));
+
+    let report = build_sksa_with_options(
+        bytes(11, 100),
+        &sksa,
+        &virage2,
+        SkCheck::Skip,
+        &Default::default(),
+    )
+    .unwrap();
+    assert_eq!(report.output.len(), sksa.len() + SA1_SIZE as usize);
+    assert_eq!(report.output[..sksa.len()], sksa);
+    assert!(decrypt_sa1(&report.output, &virage2, SkCheck::Skip).is_ok());
+}
+
+#[test]
+fn lists_each_sa() {
+    let sksa = sksa_with_sa2(0);
+
+    let sas = list_sas(&sksa).unwrap();
+    let layout: Vec<_> = sas
+        .iter()
+        .map(|(offset, cmd)| (*offset, cmd.size))
+        .collect();
+    assert_eq!(
+        layout,
+        [
+            (SK_SIZE, SA1_SIZE),
+            (SK_SIZE + SA1_INFO_BLOCK_SIZE + SA1_SIZE as usize, 0x4000)
+        ]
+    );
+}
+
+#[test]
+fn output_size_matches_the_build() {
+    let sksa = sksa_with_sa2(0x100);
+
+    let virage2 = virage2();
+    let check_sksa = |sksa: &[u8], options: BuildOptions| {
+        let report =
+            build_sksa_with_options(bytes(20, 100), sksa, &virage2, SkCheck::Skip, &options)
+                .unwrap();
+        assert_eq!(output_size(sksa, &options).unwrap(), report.output.len());
+    };
+    let check = |options| check_sksa(&sksa, options);
+    check(Default::default());
+    check_sksa(
+        &sksa[0x8000..],
+        BuildOptions {
+            sk_size: SK_SIZE - 0x8000,
+            ..Default::default()
+        },
+    );
+    check(BuildOptions {
+        sa_index: 2,
+        ..Default::default()
+    });
+    check(BuildOptions {
+        preserve_tail: true,
+        ..Default::default()
+    });
+    check(BuildOptions {
+        sa_index: 2,
+        preserve_tail: true,
+        ..Default::default()
+    });
+    check(BuildOptions {
+        sa1_only: true,
+        preserve_tail: true,
+        ..Default::default()
+    });
+    #[cfg(feature = "sign")]
+    check(BuildOptions {
+        sign_key: Some(Arc::new(bbbs::parse_sign_key(SIGN_KEY).unwrap())),
+        ..Default::default()
+    });
+}
+
+#[test]
+fn prebuilt_sa1_is_rejected_in_either_endianness() {
+    for endian in [Endian::Big, Endian::Little] {
+        let options = BuildOptions {
+            endian,
+            ..Default::default()
+        };
+        let prebuilt = make_sa1(bytes(23, 100), &options).unwrap();
+        assert!(matches!(
+            build_sksa_with_options(prebuilt, &sksa(), &virage2(), SkCheck::Skip, &options),
+            Err(BBBSError::PrebuiltPayload("SA1"))
+        ));
+    }
+}
+
+#[test]
+fn tiny_header_is_rejected() {
+    let options = BuildOptions {
+        header_size: 4,
+        ..Default::default()
+    };
+
+    assert!(matches!(
+        make_sa1(bytes(15, 100), &options),
+        Err(BBBSError::EntrypointOutOfBounds(4))
+    ));
+}
+
+#[test]
+fn dumped_plain_sa1_matches_decrypted() {
+    let fx = Fixtures::new("dump-plain");
+    fs::write(fx.path("payload.bin"), bytes(16, 3000)).unwrap();
+
+    let (sksa, virage2, payload) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+    );
+    let (built, plain) = (fx.path("out.sksa"), fx.path("plain.bin"));
+    fx.run(&[
+        "build",
+        "-s",
+        &sksa,
+        "-v",
+        &virage2,
+        "--no-verify",
+        "--dump-plain-sa1",
+        &plain,
+        &payload,
+        &built,
+    ]);
+
+    let sa1 = decrypt_sa1(
+        &fs::read(&built).unwrap(),
+        &fs::read(&virage2).unwrap(),
+        SkCheck::Skip,
+    )
+    .unwrap();
+    assert_eq!(fs::read(&plain).unwrap(), sa1);
+}
+
+#[test]
+fn dry_run_writes_nothing() {
+    let fx = Fixtures::new("dry-run");
+    fs::write(fx.path("payload.bin"), bytes(17, 3000)).unwrap();
+
+    let (sksa, virage2, payload) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+    );
+    let (built, plain, hash, summary, manifest, dump) = (
+        fx.path("out.sksa"),
+        fx.path("plain.bin"),
+        fx.path("hash.txt"),
+        fx.path("summary.json"),
+        fx.path("manifest.json"),
+        fx.path("dump"),
+    );
+    fx.run(&[
+        "build",
+        "-s",
+        &sksa,
+        "-v",
+        &virage2,
+        "--no-verify",
+        "--dry-run",
+        "--dump-plain-sa1",
+        &plain,
+        &format!("--output-hash-file={hash}"),
+        &format!("--json={summary}"),
+        "--manifest",
+        &manifest,
+        "--dump-all",
+        &dump,
+        &payload,
+        &built,
+    ]);
+
+    for output in [&built, &plain, &hash, &summary, &manifest, &dump] {
+        assert!(!Path::new(output).exists(), "{output} was written");
+    }
+}
+
+#[test]
+fn trimming_respects_min_size_and_alignment() {
+    let mut payload = vec![0; 64];
+    payload[..20].fill(0xFF);
+
+    assert_eq!(trimmed_len(&payload, 0, false), 20);
+    assert_eq!(trimmed_len(&payload, 0, true), 32);
+    assert_eq!(trimmed_len(&payload, 40, true), 40);
+    assert_eq!(trimmed_len(&payload, 100, false), 64);
+}
+
+#[test]
+fn custom_sk_size_is_used() {
+    let sk_size = 0x8000;
+    let sksa = sksa()[SK_SIZE - sk_size..].to_vec();
+
+    let build = |sk_size| {
+        SksaBuilder::new()
+            .payload(bytes(17, 100))
+            .sksa(sksa.clone())
+            .virage2(virage2())
+            .no_verify(true)
+            .sk_size(sk_size)
+            .build()
+    };
+
+    let output = build(sk_size).unwrap();
+    assert_eq!(output.len(), sksa.len());
+    assert_eq!(
+        output[..sk_size + SA1_INFO_BLOCK_SIZE],
+        sksa[..sk_size + SA1_INFO_BLOCK_SIZE]
+    );
+
+    assert!(matches!(build(100), Err(BBBSError::InvalidSKSize(100))));
+}
+
+#[test]
+fn unaligned_sa1_size_is_rejected() {
+    let mut sksa = sksa();
+    sksa[SK_SIZE + 12..SK_SIZE + 16].copy_from_slice(&(SA1_SIZE - 1).to_be_bytes());
+
+    let result = build_sksa_with_options(
+        bytes(22, 100),
+        &sksa,
+        &virage2(),
+        SkCheck::Skip,
+        &Default::default(),
+    );
+    assert!(
+        matches!(result, Err(BBBSError::SA1NotBlockAligned(len)) if len == SA1_SIZE as usize - 1)
+    );
+}
+
+#[test]
+fn dump_all_writes_each_artifact() {
+    let fx = Fixtures::new("dump-all");
+    fs::write(fx.path("payload.bin"), bytes(23, 3000)).unwrap();
+
+    let (sksa, virage2, payload) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+    );
+    let (built, dir) = (fx.path("out.sksa"), fx.path("dump"));
+    fx.run(&[
+        "build",
+        "-s",
+        &sksa,
+        "-v",
+        &virage2,
+        "--no-verify",
+        "--dump-all",
+        &dir,
+        &payload,
+        &built,
+    ]);
+
+    let dump = |name: &str| fs::read(fx.dir.join("dump").join(name)).unwrap();
+    assert_eq!(dump("output.bin"), fs::read(&built).unwrap());
+    assert_eq!(
+        dump("plain_sa1.bin"),
+        decrypt_sa1(
+            &dump("output.bin"),
+            &fs::read(&virage2).unwrap(),
+            SkCheck::Skip
+        )
+        .unwrap()
+    );
+
+    let cmd: serde_json::Value = serde_json::from_slice(&dump("cmd_head.json")).unwrap();
+    assert_eq!(cmd["size"], SA1_SIZE);
+    assert!(!fx.dir.join("dump").join("sa1_key.txt").exists());
+    assert!(!fx.dir.join("dump").join("sk.bin").exists());
+
+    // the Virage2 can only be read once from stdin, so the dump has to reuse it
+    let status = Command::new(env!("CARGO_BIN_EXE_bbbs"))
+        .args([
+            "build",
+            "-s",
+            &sksa,
+            "-v",
+            "-",
+            "--no-verify",
+            "--dump-all",
+            &dir,
+            "--dump-keys",
+            &payload,
+            &built,
+        ])
+        .stdin(fs::File::open(&virage2).unwrap())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(String::from_utf8(dump("sa1_key.txt"))
+        .unwrap()
+        .starts_with("key: "));
+}
+
+#[test]
+fn validate_accepts_only_the_original_payload() {
+    let payload = bytes(24, 2000);
+    let options = BuildOptions {
+        entrypoint: 0x80400000,
+        ..Default::default()
+    };
+    let output = build_sksa_with_options(
+        payload.clone(),
+        &sksa(),
+        &virage2(),
+        SkCheck::Skip,
+        &options,
+    )
+    .unwrap()
+    .output;
+
+    let validate = |payload: Vec<u8>| {
+        validate_sksa(
+            &output,
+            payload,
+            &virage2(),
+            SkCheck::Skip,
+            &Default::default(),
+        )
+    };
+    validate(payload.clone()).unwrap();
+
+    let start = options.payload_start();
+    let mut changed = payload.clone();
+    changed[1500] ^= 1;
+    assert!(matches!(
+        validate(changed),
+        Err(BBBSError::PayloadMismatch(i)) if i == start + 1500
+    ));
+
+    assert!(matches!(
+        validate(payload[..1999].to_vec()),
+        Err(BBBSError::PayloadMismatch(i)) if i == start + 1999
+    ));
+}
+
+#[test]
+fn validate_uses_the_build_options() {
+    let fx = Fixtures::new("validate-options");
+    fs::write(fx.path("payload.bin"), bytes(33, 3000)).unwrap();
+    fs::write(fx.path("overlay.bin"), bytes(34, 0x100)).unwrap();
+
+    let (sksa, virage2, payload, built) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+        fx.path("out.sksa"),
+    );
+    let extra = format!("10000:{}", fx.path("overlay.bin"));
+    let options = [
+        "--compress",
+        "--payload-offset",
+        "20",
+        "--pad-byte",
+        "FF",
+        "--extra-payload",
+        &extra,
+    ];
+    let keys = ["-v", &virage2, "--no-verify"];
+    fx.run(
+        &[
+            &["build", "-s", &sksa][..],
+            &keys,
+            &options,
+            &[&payload, &built],
+        ]
+        .concat(),
+    );
+
+    let validate = ["validate", "-s", &built];
+    fx.run(&[&validate[..], &keys, &options, &[&payload]].concat());
+    assert_eq!(
+        fx.code(&[&validate[..], &keys, &[&payload]].concat()),
+        Some(36)
+    );
+}
+
+#[test]
+fn manifest_describes_the_output_layout() {
+    let fx = Fixtures::new("manifest");
+    fs::write(fx.path("payload.bin"), bytes(27, 3000)).unwrap();
+
+    let (sksa, virage2, payload) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+    );
+    let (built, manifest) = (fx.path("out.sksa"), fx.path("manifest.json"));
+    fx.run(&[
+        "build",
+        "-s",
+        &sksa,
+        "-v",
+        &virage2,
+        "--no-verify",
+        "--manifest",
+        &manifest,
+        &payload,
+        &built,
+    ]);
+
+    let output = fs::read(&built).unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(&manifest).unwrap()).unwrap();
+    assert_eq!(manifest["size"], output.len());
+    assert_eq!(manifest["sk"]["size"], SK_SIZE);
+    assert_eq!(manifest["info_block"]["offset"], SK_SIZE);
+    assert_eq!(manifest["sa1"]["offset"], SK_SIZE + SA1_INFO_BLOCK_SIZE);
+    assert_eq!(manifest["sa1"]["size"], SA1_SIZE);
+    assert_eq!(manifest["payload"]["size"], 3000);
+    assert_eq!(
+        manifest["padding_bytes"],
+        SA1_SIZE as usize - BuildOptions::default().header_size - 3000
+    );
+    assert_eq!(manifest["extra_payloads"], serde_json::json!([]));
+
+    // extra payloads are listed on their own rather than counted as part of the payload
+    fs::write(fx.path("overlay.bin"), bytes(28, 0x100)).unwrap();
+    let extra = format!("10000:{}", fx.path("overlay.bin"));
+    fx.run(&[
+        "build",
+        "-s",
+        &sksa,
+        "-v",
+        &virage2,
+        "--no-verify",
+        "--extra-payload",
+        &extra,
+        "--manifest",
+        &fx.path("manifest.json"),
+        &payload,
+        &built,
+    ]);
+
+    let manifest: serde_json::Value =
+        serde_json::from_slice(&fs::read(fx.path("manifest.json")).unwrap()).unwrap();
+    let sa1 = SK_SIZE + SA1_INFO_BLOCK_SIZE;
+    assert_eq!(manifest["payload"]["size"], 3000);
+    assert_eq!(
+        manifest["extra_payloads"],
+        serde_json::json!([{ "offset": sa1 + 0x10000, "size": 0x100 }])
+    );
+}
+
+#[test]
+fn stage_hook_sees_each_stage_in_order() {
+    let stages = Arc::new(Mutex::new(vec![]));
+    let seen = stages.clone();
+
+    SksaBuilder::new()
+        .payload(bytes(30, 100))
+        .sksa(sksa())
+        .virage2(virage2())
+        .sk_hash(SK_HASH)
+        .on_stage(move |stage| seen.lock().unwrap().push(stage))
+        .build()
+        .unwrap();
+
+    assert_eq!(
+        *stages.lock().unwrap(),
+        [
+            BuildStage::ReadSksa,
+            BuildStage::VerifyHash,
+            BuildStage::DeriveKey,
+            BuildStage::EncryptSa1,
+            BuildStage::Write,
+        ]
+    );
+}
+
+#[test]
+fn verify_write_accepts_a_good_write() {
+    let fx = Fixtures::new("verify-write");
+    fs::write(fx.path("payload.bin"), bytes(29, 100)).unwrap();
+
+    let (sksa, virage2, payload, built) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("payload.bin"),
+        fx.path("out.sksa"),
+    );
+    let build = ["build", "-s", &sksa, "-v", &virage2, "--no-verify"];
+    fx.run(&[&build[..], &["--verify-write", &payload, &built]].concat());
+    fx.run(&[&build[..], &["--verify-write", "--mmap", &payload, &built]].concat());
+
+    let output = Command::new(env!("CARGO_BIN_EXE_bbbs"))
+        .args([&build[..], &["--verify-write", &payload, "-"]].concat())
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout, fs::read(&built).unwrap());
+}
+
+#[test]
+fn extra_payloads_are_placed_after_the_payload() {
+    let fx = Fixtures::new("extra-payload");
+    let (payload, overlay) = (bytes(30, 0x1000), bytes(31, 0x800));
+    fs::write(fx.path("payload.bin"), &payload).unwrap();
+    fs::write(fx.path("overlay.bin"), &overlay).unwrap();
+
+    let (sksa, virage2, built) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("out.sksa"),
+    );
+    let extra = format!("10000:{}", fx.path("overlay.bin"));
+    let payload_path = fx.path("payload.bin");
+    fx.run(&[
+        "build",
+        "-s",
+        &sksa,
+        "-v",
+        &virage2,
+        "--no-verify",
+        "--extra-payload",
+        &extra,
+        &payload_path,
+        &built,
+    ]);
+
+    let sa1 = decrypt_sa1(
+        &fs::read(&built).unwrap(),
+        &fs::read(&virage2).unwrap(),
+        SkCheck::Skip,
+    )
+    .unwrap();
+    let start = BuildOptions::default().header_size;
+    assert_eq!(sa1[start..start + payload.len()], payload);
+    assert!(sa1[start + payload.len()..0x10000].iter().all(|&b| b == 0));
+    assert_eq!(sa1[0x10000..0x10800], overlay);
+}
+
+#[test]
+fn colliding_extra_payloads_are_rejected() {
+    let build = |extra: &[(usize, usize)]| {
+        let options = BuildOptions {
+            extra_payloads: extra
+                .iter()
+                .map(|&(offset, len)| (offset, vec![0xEE; len]))
+                .collect(),
+            ..Default::default()
+        };
+        build_sksa_with_options(
+            bytes(32, 0x1000),
+            &sksa(),
+            &virage2(),
+            SkCheck::Skip,
+            &options,
+        )
+    };
+
+    let payload_end = BuildOptions::default().header_size + 0x1000;
+    assert!(matches!(
+        build(&[(payload_end - 1, 0x10)]),
+        Err(BBBSError::PayloadOverlap(offset, end)) if offset == payload_end - 1 && end == payload_end
+    ));
+    assert!(matches!(
+        build(&[(0x8000, 0x10), (0x4000, 0x4001)]),
+        Err(BBBSError::PayloadOverlap(0x8000, 0x8001))
+    ));
+    assert!(matches!(
+        build(&[(SA1_SIZE as usize - 0x10, 0x11)]),
+        Err(BBBSError::ExtraPayloadTooLong(end, SA1_SIZE)) if end == SA1_SIZE as usize + 1
+    ));
+
+    let output = build(&[(payload_end, 0x10), (SA1_SIZE as usize - 0x10, 0x10)]).unwrap();
+    let sa1 = decrypt_sa1(&output.output, &virage2(), SkCheck::Skip).unwrap();
+    assert_eq!(sa1[payload_end..payload_end + 0x10], [0xEE; 0x10]);
+    assert_eq!(sa1[SA1_SIZE as usize - 0x10..], [0xEE; 0x10]);
+}
+
+#[test]
+fn patch_changes_only_the_patched_bytes() {
+    let fx = Fixtures::new("patch");
+    let (sksa, virage2, patched) = (
+        fx.path("in.sksa"),
+        fx.path("virage2.bin"),
+        fx.path("out.sksa"),
+    );
+    fx.run(&[
+        "patch",
+        "-v",
+        &virage2,
+        "--no-verify",
+        "--patch",
+        "1008:80301000",
+        "--patch",
+        "0x2000:DEADBEEF",
+        &sksa,
+        &patched,
+    ]);
+
+    let (sksa, virage2) = (fs::read(&sksa).unwrap(), fs::read(&virage2).unwrap());
+    let original = decrypt_sa1(&sksa, &virage2, SkCheck::Skip).unwrap();
+    let mut expected = original.clone();
+    expected[0x1008..0x100C].copy_from_slice(&[0x80, 0x30, 0x10, 0x00]);
+    expected[0x2000..0x2004].copy_from_slice(&[0xDE, 0xAD, 0xBE, 0xEF]);
+
+    let output = fs::read(&patched).unwrap();
+    assert_eq!(output.len(), sksa.len());
+    assert_eq!(
+        output[..SK_SIZE + SA1_INFO_BLOCK_SIZE],
+        sksa[..SK_SIZE + SA1_INFO_BLOCK_SIZE]
+    );
+    assert_eq!(
+        decrypt_sa1(&output, &virage2, SkCheck::Skip).unwrap(),
+        expected
+    );
+
+    let patch = [(SA1_SIZE as usize - 2, vec![0; 3])];
+    assert!(matches!(
+        patch_sksa(&sksa, &virage2, SkCheck::Skip, &patch, &Default::default()),
+        Err(BBBSError::PatchOutOfBounds(offset, 3, len))
+            if offset == SA1_SIZE as usize - 2 && len == SA1_SIZE as usize
+    ));
+}
@@ -0,0 +1,89 @@
+use bbbs::crypto::AES_BLOCK_SIZE;
+use bbbs::{
+    build_sksa_with_options, BBBSError, BuildReport, SkCheck, ROM_HEADER_SIZE, SKSA_MIN_BYTES,
+    SK_SIZE,
+};
+use proptest::prelude::*;
+
+/// Largest SA1 to generate; big enough to cover several header-sized steps while keeping each case
+/// cheap to encrypt
+const MAX_SA1_SIZE: usize = 0x10000;
+
+fn sksa(sa1_size: u32) -> Vec<u8> {
+    let mut rv = vec![0; SKSA_MIN_BYTES + sa1_size as usize];
+    rv[SK_SIZE + 12..SK_SIZE + 16].copy_from_slice(&sa1_size.to_be_bytes());
+    rv
+}
+
+fn virage2() -> Vec<u8> {
+    let mut rv: Vec<u8> = (0..=255).collect();
+    rv[248..252].fill(0);
+
+    let sum = rv
+        .chunks_exact(4)
+        .map(|w| u32::from_be_bytes(w.try_into().unwrap()))
+        .fold(0u32, u32::wrapping_add);
+    rv[248..252].copy_from_slice(&0x00BBC0DEu32.wrapping_sub(sum).to_be_bytes());
+    rv
+}
+
+fn build(payload_len: usize, sa1_size: u32) -> Result<BuildReport, BBBSError> {
+    build_sksa_with_options(
+        vec![0xA5; payload_len],
+        &sksa(sa1_size),
+        &virage2(),
+        SkCheck::Skip,
+        &Default::default(),
+    )
+}
+
+/// An aligned SA1 size that leaves room for the ROM header and at least one block of payload
+fn aligned_sa1_size() -> impl Strategy<Value = u32> {
+    (ROM_HEADER_SIZE / AES_BLOCK_SIZE + 1..=MAX_SA1_SIZE / AES_BLOCK_SIZE)
+        .prop_map(|blocks| (blocks * AES_BLOCK_SIZE) as u32)
+}
+
+/// The space left for the payload after the ROM header
+fn room(sa1_size: u32) -> usize {
+    sa1_size as usize - ROM_HEADER_SIZE
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    // payloads are never empty, which is rejected before anything about the size is checked
+    #[test]
+    fn fitting_payloads_fill_exactly_the_sa1(
+        (payload_len, sa1_size) in aligned_sa1_size().prop_flat_map(|s| (1..=room(s), Just(s))),
+    ) {
+        let report = build(payload_len, sa1_size).unwrap();
+        prop_assert_eq!(report.output.len(), SKSA_MIN_BYTES + sa1_size as usize);
+        prop_assert_eq!(report.padded_len, sa1_size as usize);
+        prop_assert!(report.padded_len.is_multiple_of(AES_BLOCK_SIZE));
+        prop_assert_eq!(report.padding, room(sa1_size) - payload_len);
+    }
+
+    #[test]
+    fn oversized_payloads_are_rejected(sa1_size in aligned_sa1_size(), excess in 1..=0x1000usize) {
+        let payload_len = room(sa1_size) + excess;
+
+        let result = build(payload_len, sa1_size);
+        prop_assert!(
+            matches!(result, Err(BBBSError::PayloadTooLong(len, max)) if len == payload_len && max as usize == room(sa1_size)),
+            "{result:?}"
+        );
+    }
+
+    #[test]
+    fn unaligned_sa1_sizes_are_rejected(
+        (payload_len, sa1_size) in (ROM_HEADER_SIZE as u32 + 1..=MAX_SA1_SIZE as u32)
+            .prop_filter("unaligned", |s| !(*s as usize).is_multiple_of(AES_BLOCK_SIZE))
+            .prop_flat_map(|s| (1..=room(s), Just(s))),
+    ) {
+        let result = build(payload_len, sa1_size);
+        prop_assert!(
+            matches!(result, Err(BBBSError::SA1NotBlockAligned(len)) if len == sa1_size as usize),
+            "{result:?}"
+        );
+    }
+}